@@ -0,0 +1,178 @@
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use tokio::io::AsyncReadExt;
+
+// Files larger than this are read from disk on every transfer, so that a
+// handful of big ones can't use up the memory
+const MAX_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+
+struct CachedFile {
+    path: PathBuf,
+    modified: SystemTime,
+    contents: Arc<[u8]>,
+}
+
+/// Keeps the contents of the most recently served files in memory, so
+/// that files requested over and over (e.g. by a PXE fleet) don't need to
+/// be opened and read for every transfer. An entry is dropped as soon as
+/// the file on disk is seen with a different modification time or size
+#[derive(Clone)]
+pub struct FileCache {
+    capacity: usize,
+    max_entry_size: u64,
+    // Least recently used first
+    entries: Arc<Mutex<Vec<CachedFile>>>,
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> Self {
+        FileCache {
+            capacity,
+            max_entry_size: MAX_ENTRY_SIZE,
+            entries: Arc::new(Mutex::new(Vec::with_capacity(capacity))),
+        }
+    }
+
+    /// Returns the contents of the file, reading it only when it's not
+    /// cached or has changed. Anything but regular files is not cached,
+    /// and neither are large files, and `None` is returned for them
+    pub async fn open(&self, path: &Path) -> std::io::Result<Option<Cursor<Arc<[u8]>>>> {
+        let metadata = tokio::fs::metadata(path).await?;
+        if !metadata.is_file() || metadata.len() > self.max_entry_size {
+            return Ok(None);
+        }
+        let modified = metadata.modified()?;
+
+        if let Some(contents) = self.lookup(path, modified, metadata.len()) {
+            return Ok(Some(Cursor::new(contents)));
+        }
+
+        // The file may change while it's read, in which case the contents
+        // can't be trusted to match any modification time
+        let mut file = tokio::fs::File::open(path).await?;
+        let before = file.metadata().await?;
+        let mut contents = vec![];
+        (&mut file).take(self.max_entry_size + 1).read_to_end(&mut contents).await?;
+        let after = file.metadata().await?;
+        let modified = after.modified()?;
+        if contents.len() as u64 > self.max_entry_size
+            || before.modified()? != modified
+            || after.len() != contents.len() as u64 {
+            return Ok(None);
+        }
+        let contents: Arc<[u8]> = contents.into();
+        self.insert(path, modified, contents.clone());
+
+        Ok(Some(Cursor::new(contents)))
+    }
+
+    fn lookup(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Arc<[u8]>> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|entry| entry.path == path)?;
+        let entry = entries.remove(index);
+
+        if entry.modified != modified || entry.contents.len() as u64 != len {
+            return None;
+        }
+        let contents = entry.contents.clone();
+        entries.push(entry);
+
+        Some(contents)
+    }
+
+    fn insert(&self, path: &Path, modified: SystemTime, contents: Arc<[u8]>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.path != path);
+        if entries.len() >= self.capacity {
+            entries.remove(0);
+        }
+        entries.push(CachedFile { path: path.to_owned(), modified, contents });
+    }
+}
+
+impl std::fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileCache").field("capacity", &self.capacity).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn write_with_mtime(path: &Path, contents: &[u8], modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cache_hit_and_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        write_with_mtime(&path, b"original", modified);
+
+        let cache = FileCache::new(4);
+        let first = cache.open(&path).await.unwrap().unwrap();
+        assert_eq!(&first.get_ref()[..], b"original");
+
+        // Same size and modification time: still served from memory
+        write_with_mtime(&path, b"replaced", modified);
+        let second = cache.open(&path).await.unwrap().unwrap();
+        assert_eq!(&second.get_ref()[..], b"original");
+
+        write_with_mtime(&path, b"replaced", modified + Duration::from_secs(1));
+        let third = cache.open(&path).await.unwrap().unwrap();
+        assert_eq!(&third.get_ref()[..], b"replaced");
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let paths = ["a", "b", "c"].map(|name| dir.path().join(name));
+        for path in &paths {
+            write_with_mtime(path, b"old", modified);
+        }
+
+        let cache = FileCache::new(2);
+        cache.open(&paths[0]).await.unwrap();
+        cache.open(&paths[1]).await.unwrap();
+        cache.open(&paths[0]).await.unwrap();
+        cache.open(&paths[2]).await.unwrap();
+
+        // "b" was the least recently used one, so it's the one re-read
+        for path in &paths {
+            write_with_mtime(path, b"new", modified);
+        }
+        assert_eq!(&cache.open(&paths[0]).await.unwrap().unwrap().get_ref()[..], b"old");
+        assert_eq!(&cache.open(&paths[1]).await.unwrap().unwrap().get_ref()[..], b"new");
+    }
+
+    #[tokio::test]
+    async fn large_files_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, b"too large").unwrap();
+
+        let cache = FileCache { max_entry_size: 4, ..FileCache::new(2) };
+        assert!(cache.open(&path).await.unwrap().is_none());
+        assert!(cache.entries.lock().unwrap().is_empty());
+
+        std::fs::write(&path, b"fits").unwrap();
+        assert_eq!(&cache.open(&path).await.unwrap().unwrap().get_ref()[..], b"fits");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn special_files_not_cached() {
+        let cache = FileCache::new(2);
+        assert!(cache.open(Path::new("/dev/null")).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{net::SocketAddr, path::Path};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    time::{timeout, Duration},
+};
+
+use crate::{parse_message, Message, Mode, TftpOption};
+
+const BLOCK_SIZE: usize = 512;
+const MAX_ATTEMPTS: usize = 5;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Minimal client, only able to download files. Meant for testing and
+/// tooling, not as a fully featured implementation
+#[derive(Debug, Clone)]
+pub struct Client {
+    server: SocketAddr,
+    options: Vec<TftpOption>,
+    timeout: Duration,
+}
+
+fn protocol_error(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+impl Client {
+    pub fn new(server: SocketAddr) -> Self {
+        Client { server, options: vec![], timeout: DEFAULT_TIMEOUT }
+    }
+
+    /// Option to request from the server
+    pub fn option(mut self, option: TftpOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    /// How long to wait for an answer before sending the last packet again
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Downloads a file into `local`, returning the number of bytes
+    /// received
+    pub async fn download(&self, filename: &str, local: &Path) -> std::io::Result<u64> {
+        let mut file = tokio::fs::File::create(local).await?;
+        let size = self.get(filename, &mut file).await?;
+        file.flush().await?;
+
+        Ok(size)
+    }
+
+    /// Downloads a file, writing its contents as they arrive
+    pub async fn get<W: AsyncWrite + Unpin>(&self, filename: &str, mut writer: W) -> std::io::Result<u64> {
+        let local_addr: SocketAddr = match self.server {
+            SocketAddr::V4(..) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(..) => "[::]:0".parse().unwrap(),
+        };
+        let sock = UdpSocket::bind(local_addr).await?;
+        let request = Message::Read {
+            filename: filename.into(),
+            mode: Mode::Octet,
+            options: self.options.clone(),
+            ignored: vec![],
+        }.into_packet();
+
+        // Largest packet the server may send before the options are known
+        let max_block_size = self.options.iter()
+            .find_map(|opt| match opt {
+                TftpOption::BlockSize(size) => Some(*size as usize),
+                _ => None,
+            })
+            .unwrap_or(BLOCK_SIZE)
+            .max(BLOCK_SIZE);
+        let mut buf = vec![0; max_block_size + 4];
+
+        // The transfer continues from the port the server answers from
+        let mut last_packet = request;
+        let mut attempts = 0;
+        let (len, server) = loop {
+            sock.send_to(&last_packet, self.server).await?;
+            match timeout(self.timeout, sock.recv_from(&mut buf)).await {
+                Ok(received) => break received?,
+                Err(_) => {
+                    attempts += 1;
+                    if attempts == MAX_ATTEMPTS {
+                        return Err(std::io::ErrorKind::TimedOut.into());
+                    }
+                }
+            }
+        };
+        sock.connect(server).await?;
+
+        let mut block_size = BLOCK_SIZE;
+        let mut expected_block: u16 = 1;
+        let mut received: u64 = 0;
+        let mut len = len;
+        loop {
+            match parse_message(&buf[..len]).map_err(|error| protocol_error(error.to_string()))? {
+                Message::OptionAck { options } if expected_block == 1 => {
+                    for option in options {
+                        if let TftpOption::BlockSize(size) = option {
+                            block_size = size as usize;
+                        }
+                    }
+                    last_packet = Message::Ack(0).into_packet();
+                    sock.send(&last_packet).await?;
+                }
+                Message::Data { block, payload } if block == expected_block => {
+                    writer.write_all(&payload).await?;
+                    received = received.saturating_add(payload.len() as u64);
+                    last_packet = Message::Ack(block).into_packet();
+                    sock.send(&last_packet).await?;
+                    if payload.len() < block_size {
+                        return Ok(received);
+                    }
+                    expected_block = expected_block.wrapping_add(1);
+                }
+                Message::Error { code, message } => {
+                    return Err(protocol_error(format!("{code:?}: {message}")));
+                }
+                // Duplicates are expected if our ACKs get lost
+                Message::Data { .. } | Message::OptionAck { .. } => {}
+                message => return Err(protocol_error(format!("unexpected {message:?}"))),
+            }
+
+            attempts = 0;
+            len = loop {
+                match timeout(self.timeout, sock.recv(&mut buf)).await {
+                    Ok(received) => break received?,
+                    Err(_) => {
+                        attempts += 1;
+                        if attempts == MAX_ATTEMPTS {
+                            return Err(std::io::ErrorKind::TimedOut.into());
+                        }
+                        sock.send(&last_packet).await?;
+                    }
+                }
+            };
+        }
+    }
+}
@@ -1,10 +1,34 @@
-#[derive(Debug)]
-enum PacketType {
+pub mod cache;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod metrics;
+pub mod ratelimit;
+pub mod reaper;
+pub mod schedule;
+pub mod server;
+pub mod source;
+pub mod trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
     ReadRequest,
     WriteRequest,
     Data,
     Acknowledgement,
     Error,
+    OptionAcknowledgement,
+}
+
+impl PacketType {
+    /// Classifies a packet by its opcode, without parsing the rest of it
+    pub fn from_packet(buffer: &[u8]) -> Result<PacketType, ParseError> {
+        if buffer.len() < 2 {
+            return Err(ParseError::Truncated("missing opcode".into()));
+        }
+
+        let opcode = u16::from_be_bytes([buffer[0], buffer[1]]);
+        PacketType::try_from(opcode).map_err(|_| ParseError::InvalidOpcode(opcode))
+    }
 }
 
 impl TryFrom<u16> for PacketType {
@@ -17,6 +41,7 @@ impl TryFrom<u16> for PacketType {
             3 => Ok(PacketType::Data),
             4 => Ok(PacketType::Acknowledgement),
             5 => Ok(PacketType::Error),
+            6 => Ok(PacketType::OptionAcknowledgement),
             _ => Err(())
         }
     }
@@ -29,11 +54,21 @@ pub enum Mode {
     Mail,
 }
 
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::NetAscii => "netascii",
+            Mode::Octet => "octet",
+            Mode::Mail => "mail",
+        }
+    }
+}
+
 impl TryFrom<&str> for Mode {
     type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match (value.to_lowercase()).as_str() {
+        match value.trim().to_lowercase().as_str() {
             "netascii" => Ok(Mode::NetAscii),
             "octet" => Ok(Mode::Octet),
             "mail" => Ok(Mode::Mail),
@@ -42,7 +77,7 @@ impl TryFrom<&str> for Mode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorCode {
     NotDefined,
     FileNotFound,
@@ -53,6 +88,42 @@ pub enum ErrorCode {
     FileAlreadyExists,
     NoSuchUser,
     OptionNegotiationError,
+    /// Codes outside the range defined by the RFCs, kept as received
+    Other(u16),
+}
+
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => ErrorCode::NotDefined,
+            1 => ErrorCode::FileNotFound,
+            2 => ErrorCode::AccessViolation,
+            3 => ErrorCode::DiskFull,
+            4 => ErrorCode::IllegalOperation,
+            5 => ErrorCode::UnknownTransferId,
+            6 => ErrorCode::FileAlreadyExists,
+            7 => ErrorCode::NoSuchUser,
+            8 => ErrorCode::OptionNegotiationError,
+            other => ErrorCode::Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::NotDefined => 0,
+            ErrorCode::FileNotFound => 1,
+            ErrorCode::AccessViolation => 2,
+            ErrorCode::DiskFull => 3,
+            ErrorCode::IllegalOperation => 4,
+            ErrorCode::UnknownTransferId => 5,
+            ErrorCode::FileAlreadyExists => 6,
+            ErrorCode::NoSuchUser => 7,
+            ErrorCode::OptionNegotiationError => 8,
+            ErrorCode::Other(code) => code,
+        }
+    }
 }
 
 impl ErrorCode {
@@ -68,6 +139,7 @@ impl ErrorCode {
                 ErrorCode::FileAlreadyExists => "File already exists",
                 ErrorCode::NoSuchUser => "No such user",
                 ErrorCode::OptionNegotiationError => "Error during option negotiation",
+                ErrorCode::Other(_) => "Unknown error",
             }.into(),
             code: self,
         }
@@ -80,8 +152,8 @@ impl ErrorCode {
 
 #[derive(Debug)]
 pub enum Message {
-    Read { filename: String, mode: Mode, options: Vec<TftpOption> },
-    Write { filename: String, mode: Mode, options: Vec<TftpOption> },
+    Read { filename: String, mode: Mode, options: Vec<TftpOption>, ignored: Vec<String> },
+    Write { filename: String, mode: Mode, options: Vec<TftpOption>, ignored: Vec<String> },
     Data { block: u16, payload: Vec<u8> },
     Ack(u16),
     Error { code: ErrorCode, message: String },
@@ -94,6 +166,7 @@ impl Message {
             filename: args.filename,
             mode: args.mode,
             options: args.options,
+            ignored: args.ignored,
         }
     }
 
@@ -102,51 +175,84 @@ impl Message {
             filename: args.filename,
             mode: args.mode,
             options: args.options,
+            ignored: args.ignored,
         }
     }
 
+    /// Builds an OACK, checking that the options can be acknowledged:
+    /// values must be within bounds, and each option may appear only once.
+    /// Exact repetitions are merged, but conflicting ones are an error
+    pub fn option_ack(options: Vec<TftpOption>) -> Result<Message, NegotiationError> {
+        Ok(Message::OptionAck { options: check_options(options)? })
+    }
+
     pub fn into_packet(self) -> Vec<u8> {
         match self {
-            // Message::Read { filename, mode } => todo!(),
-            // Message::Write { filename, mode } => todo!(),
+            // Ignored options are not sent back
+            Message::Read { filename, mode, options, .. } => {
+                encode_request(1, &filename, &mode, &options)
+            }
+            Message::Write { filename, mode, options, .. } => {
+                encode_request(2, &filename, &mode, &options)
+            }
             Message::Data { block, payload } => {
                 3_u16.to_be_bytes().into_iter()
                     .chain(block.to_be_bytes())
                     .chain(payload)
                     .collect()
             }
-            // Message::AckMessage(_) => todo!(),
+            Message::Ack(block) => {
+                4_u16.to_be_bytes().into_iter()
+                    .chain(block.to_be_bytes())
+                    .collect()
+            }
             Message::Error { code, message } => {
                 5_u16.to_be_bytes().into_iter()
-                    .chain((code as u16).to_be_bytes())
+                    .chain(u16::from(code).to_be_bytes())
                     .chain(message.bytes())
                     .chain([0])
                     .collect()
             }
             Message::OptionAck { options } => {
-                let encoded_options = options.iter().map(|op| op.encode());
+                // Unknown options are never acknowledged (RFC 2347)
+                let encoded_options = options.iter()
+                    .filter(|op| !matches!(op, TftpOption::Unknown { .. }))
+                    .map(|op| op.encode());
                 6_u16.to_be_bytes().into_iter()
                     .chain(encoded_options.flatten())
                     .collect()
             }
-            _ => todo!()
         }
     }
 }
 
+fn encode_request(opcode: u16, filename: &str, mode: &Mode, options: &[TftpOption]) -> Vec<u8> {
+    opcode.to_be_bytes().into_iter()
+        .chain(filename.bytes())
+        .chain([0])
+        .chain(mode.name().bytes())
+        .chain([0])
+        .chain(options.iter().flat_map(|op| op.encode()))
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum ParseError {
-    CorruptPacket(String),
+    Truncated(String),
+    MissingFields(String),
     InvalidOpcode(u16),
     InvalidString(String),
+    InvalidOption(String),
 }
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ParseError::CorruptPacket(string) => write!(f, "Corrupt packet: {string}"),
+            ParseError::Truncated(string) => write!(f, "Truncated packet: {string}"),
+            ParseError::MissingFields(string) => write!(f, "Missing fields: {string}"),
             ParseError::InvalidOpcode(opcode) => write!(f, "Invalid opcode: {opcode}"),
             ParseError::InvalidString(stream) => write!(f, "Invalid string: {stream:?}"),
+            ParseError::InvalidOption(option) => write!(f, "Invalid option: {option}"),
         }
     }
 }
@@ -154,6 +260,48 @@ impl std::fmt::Display for ParseError {
 impl std::error::Error for ParseError {
 }
 
+#[derive(Debug)]
+pub enum NegotiationError {
+    // The same option, with different values
+    Duplicate(String),
+    InvalidValue(String),
+    // Unknown options can't be acknowledged
+    Unknown(String),
+}
+
+impl std::fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NegotiationError::Duplicate(name) => write!(f, "Conflicting values for option: {name}"),
+            NegotiationError::InvalidValue(option) => write!(f, "Invalid option value: {option}"),
+            NegotiationError::Unknown(name) => write!(f, "Unknown option: {name}"),
+        }
+    }
+}
+
+impl std::error::Error for NegotiationError {
+}
+
+// Validates a set of options to be acknowledged, dropping repetitions
+pub(crate) fn check_options(options: Vec<TftpOption>) -> Result<Vec<TftpOption>, NegotiationError> {
+    let mut checked: Vec<TftpOption> = Vec::with_capacity(options.len());
+    for option in options {
+        if let TftpOption::Unknown { name, .. } = &option {
+            return Err(NegotiationError::Unknown(name.clone()));
+        }
+        if let Err(ParseError::InvalidOption(option)) = option.validate() {
+            return Err(NegotiationError::InvalidValue(option));
+        }
+        match checked.iter().find(|seen| seen.name() == option.name()) {
+            Some(seen) if seen.encoded_value() == option.encoded_value() => {}
+            Some(_) => return Err(NegotiationError::Duplicate(option.name())),
+            None => checked.push(option),
+        }
+    }
+
+    Ok(checked)
+}
+
 fn extract_strings(buffer: &[u8]) -> Vec<String> {
     buffer
         .split(|&c| c == 0)
@@ -161,11 +309,37 @@ fn extract_strings(buffer: &[u8]) -> Vec<String> {
         .collect()
 }
 
+// Longest mode accepted, leaving room for some padding around "netascii"
+const MAX_MODE_LENGTH: usize = 16;
+/// Smallest block size accepted by RFC 2348
+pub const MIN_BLOCK_SIZE: u16 = 8;
+/// Largest block size accepted by RFC 2348, so that a DATA packet fits in
+/// a single UDP datagram
+pub const MAX_BLOCK_SIZE: u16 = 65464;
+/// Timeout bounds (in seconds) accepted by RFC 2349
+pub const MIN_TIMEOUT: u8 = 1;
+pub const MAX_TIMEOUT: u8 = 255;
+/// Bounds for the timeout in microseconds ("utimeout" extension). Anything
+/// under a millisecond would just flood the client with retransmissions
+pub const MIN_UTIMEOUT: u32 = 1_000;
+pub const MAX_UTIMEOUT: u32 = MAX_TIMEOUT as u32 * 1_000_000;
+/// Highest block number the counter can wrap to
+pub const MAX_ROLLOVER: u8 = 1;
+/// Largest request packet, including all its options (RFC 2347)
+pub const MAX_REQUEST_SIZE: usize = 512;
+
 #[derive(Debug, Clone)]
 pub enum TftpOption {
     BlockSize(u16),
     Timeout(u8),
+    MicroTimeout(u32),
     TransferSize(u64),
+    Rollover(u8),
+    // Block the transfer starts from, skipping the previous ones. Not
+    // standard, meant for clients resuming a previous transfer
+    StartBlock(u16),
+    // Only produced when asked for, see `ParserFlags`
+    Unknown { name: String, value: String },
 }
 
 impl TftpOption {
@@ -173,7 +347,11 @@ impl TftpOption {
         match self {
             TftpOption::BlockSize(..) => "blksize",
             TftpOption::Timeout(..) => "timeout",
+            TftpOption::MicroTimeout(..) => "utimeout",
             TftpOption::TransferSize(..) => "tsize",
+            TftpOption::Rollover(..) => "rollover",
+            TftpOption::StartBlock(..) => "startblock",
+            TftpOption::Unknown { name, .. } => name,
         }.into()
     }
 
@@ -181,38 +359,111 @@ impl TftpOption {
         match self {
             TftpOption::BlockSize(sz) => sz.to_string(),
             TftpOption::Timeout(tout) => tout.to_string(),
+            TftpOption::MicroTimeout(tout) => tout.to_string(),
             TftpOption::TransferSize(tsize) => tsize.to_string(),
+            TftpOption::Rollover(rollover) => rollover.to_string(),
+            TftpOption::StartBlock(block) => block.to_string(),
+            TftpOption::Unknown { value, .. } => value.clone(),
         }.bytes().collect()
     }
 
+    /// Checks that the value is within the bounds defined for the option
+    pub fn validate(&self) -> Result<(), ParseError> {
+        let valid = match self {
+            TftpOption::BlockSize(sz) => (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(sz),
+            TftpOption::Timeout(tout) => (MIN_TIMEOUT..=MAX_TIMEOUT).contains(tout),
+            TftpOption::MicroTimeout(tout) => (MIN_UTIMEOUT..=MAX_UTIMEOUT).contains(tout),
+            // RFC 2349 does not define an upper limit
+            TftpOption::TransferSize(..) => true,
+            TftpOption::Rollover(rollover) => *rollover <= MAX_ROLLOVER,
+            TftpOption::StartBlock(block) => *block >= 1,
+            TftpOption::Unknown { .. } => true,
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(ParseError::InvalidOption(self.to_string()))
+        }
+    }
+
     fn encode(&self) -> Vec<u8> {
         self.name()
             .bytes()
             .chain([0])
-            .chain(self.encoded_value().into_iter())
+            .chain(self.encoded_value())
             .chain([0])
             .collect()
     }
 }
 
-fn parse_option(name: &str, value: &str) -> Option<TftpOption> {
-    match name.to_lowercase().as_str() {
-        "blksize" => { // Following RFC 2348
-            value.parse::<u16>()
-                .ok()
-                .filter(|&val| (val > 7 && val < 65465))
-                .and_then(|val| Some(TftpOption::BlockSize(val)))
-        }
-        "timeout" => { // Following RFC 2349
-            value.parse::<u8>()
-                .ok()
-                .filter(|&val| val > 0)
-                .and_then(|val| Some(TftpOption::Timeout(val)))
+impl std::fmt::Display for TftpOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name(), String::from_utf8_lossy(&self.encoded_value()))
+    }
+}
+
+/// Parser behavior beyond what the RFCs mandate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParserFlags {
+    /// Keep unrecognized options as `TftpOption::Unknown`, instead of
+    /// just listing their names as ignored
+    pub keep_unknown_options: bool,
+    /// Reject requests with options where none of them can be made sense
+    /// of, instead of going on as if there were no options. Unknown
+    /// options are well formed, so they still count
+    pub strict_options: bool,
+}
+
+// Numeric option values. Surrounding whitespace is tolerated, as some
+// clients pad them, but anything other than plain digits (including a
+// sign, which `str::parse` would accept) makes the value invalid
+fn parse_number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok()
+}
+
+// What an option sent by a client amounts to
+enum OptionKind {
+    Known(TftpOption),
+    // Not one the server knows about. It's only taken as an option if it
+    // looks like one, with a name and value made of printable characters
+    Unknown { well_formed: bool },
+    // Known, but with an unacceptable value
+    Invalid,
+}
+
+fn classify_option(name: &str, value: &str) -> OptionKind {
+    let parsed = match name.to_lowercase().as_str() {
+        "blksize" => parse_number(value).map(TftpOption::BlockSize),
+        "timeout" => parse_number(value).map(TftpOption::Timeout),
+        "utimeout" => parse_number(value).map(TftpOption::MicroTimeout),
+        "tsize" => parse_number(value).map(TftpOption::TransferSize),
+        "rollover" => parse_number(value).map(TftpOption::Rollover),
+        "startblock" => parse_number(value).map(TftpOption::StartBlock),
+        _ => {
+            let well_formed = [name, value]
+                .iter()
+                .all(|string| !string.is_empty() && string.chars().all(|c| c.is_ascii_graphic()));
+            return OptionKind::Unknown { well_formed };
         }
-        "tsize" => {  // Following RFC 2394 - does not define upper limit
-            value.parse::<u64>()
-                .ok()
-                .and_then(|val| Some(TftpOption::TransferSize(val)))
+    };
+
+    match parsed {
+        Some(option) if option.validate().is_ok() => OptionKind::Known(option),
+        _ => OptionKind::Invalid,
+    }
+}
+
+fn parse_option(name: &str, value: &str, flags: ParserFlags) -> Option<TftpOption> {
+    match classify_option(name, value) {
+        OptionKind::Known(option) => Some(option),
+        OptionKind::Unknown { .. } if flags.keep_unknown_options => {
+            Some(TftpOption::Unknown { name: name.into(), value: value.into() })
         }
         _ => None,
     }
@@ -222,58 +473,117 @@ struct Arguments {
     filename: String,
     mode: Mode,
     options: Vec<TftpOption>,
+    // Names of the options that were unknown or had unacceptable values,
+    // which RFC 2347 mandates to ignore
+    ignored: Vec<String>,
 }
 
-fn parse_readwrite(buffer: &[u8]) -> Result<Arguments, ParseError> {
+fn parse_readwrite(buffer: &[u8], flags: ParserFlags) -> Result<Arguments, ParseError> {
     if buffer.len() < 4 {
-        return Err(ParseError::CorruptPacket("Too short packet".into()));
+        return Err(ParseError::Truncated("Read/Write arguments too short".into()));
     }
 
     let strings = extract_strings(buffer);
 
     if strings.len() < 2 {
-        Err(ParseError::CorruptPacket("Missing arguments".into()))
+        Err(ParseError::MissingFields("expected filename and mode".into()))
     } else {
         let filename = strings[0].clone();
         let possible_mode = &strings[1];
+        // Not even looked at if it's way longer than any valid mode, and
+        // not echoed back either
+        if possible_mode.len() > MAX_MODE_LENGTH {
+            return Err(ParseError::InvalidString(format!("mode too long ({} bytes)", possible_mode.len())));
+        }
         let mode = match Mode::try_from(possible_mode.as_str()) {
             Ok(mode) => mode,
+            // The mode always comes right after the filename (RFC 2347),
+            // point it out if the client sent an option there instead
+            Err(_) if strings.get(2).is_some_and(|value| parse_option(possible_mode, value, ParserFlags::default()).is_some()) => {
+                return Err(ParseError::InvalidOption(format!("{possible_mode} found in place of the mode")));
+            }
             Err(_) => return Err(ParseError::InvalidString(possible_mode.into())),
         };
-        let options = strings[2..]
-            .chunks(2)
-            .filter(|chunk| chunk.len() == 2) // To discard leftovers
-            .map(|chunk| parse_option(&chunk[0], &chunk[1]))
-            .flatten()
-            .collect::<Vec<_>>();
+        let mut options = vec![];
+        let mut ignored = vec![];
+        let mut usable = false;
+        for chunk in strings[2..].chunks(2) {
+            // Discard leftovers and padding
+            if chunk.len() < 2 || chunk[0].is_empty() {
+                continue;
+            }
+            match classify_option(&chunk[0], &chunk[1]) {
+                OptionKind::Known(option) => {
+                    usable = true;
+                    options.push(option);
+                }
+                OptionKind::Unknown { well_formed } => {
+                    usable |= well_formed;
+                    if flags.keep_unknown_options {
+                        options.push(TftpOption::Unknown { name: chunk[0].clone(), value: chunk[1].clone() });
+                    } else {
+                        ignored.push(chunk[0].clone());
+                    }
+                }
+                OptionKind::Invalid => ignored.push(chunk[0].clone()),
+            }
+        }
+        if flags.strict_options && !usable && strings[2..].iter().any(|string| !string.is_empty()) {
+            return Err(ParseError::InvalidOption("no usable options".into()));
+        }
 
         Ok(Arguments {
             filename,
             mode,
             options,
+            ignored,
         })
     }
 }
 
+// Options the server doesn't acknowledge are simply not there, and
+// anything unexpected is dropped as well
+fn parse_option_ack(buffer: &[u8]) -> Vec<TftpOption> {
+    extract_strings(buffer)
+        .chunks(2)
+        .filter(|chunk| chunk.len() == 2)
+        .filter_map(|chunk| parse_option(&chunk[0], &chunk[1], ParserFlags::default()))
+        .collect()
+}
+
 pub fn parse_message(buffer: &[u8]) -> Result<Message, ParseError> {
+    parse_message_with(buffer, ParserFlags::default())
+}
+
+pub fn parse_message_with(buffer: &[u8], flags: ParserFlags) -> Result<Message, ParseError> {
     if buffer.len() < 4 {
-        return Err(ParseError::CorruptPacket("Truncated Read/Write packet".into()));
+        return Err(ParseError::Truncated("shorter than the minimum packet size".into()));
     }
 
     // Interpret the opcode
     Ok(match u16::from_be_bytes([buffer[0], buffer[1]]) {
-        1 => Message::read_from_arguments(parse_readwrite(&buffer[2..])?),
-        2 => Message::write_from_arguments(parse_readwrite(&buffer[2..])?),
-        3 => { todo!() },
+        1 => Message::read_from_arguments(parse_readwrite(&buffer[2..], flags)?),
+        2 => Message::write_from_arguments(parse_readwrite(&buffer[2..], flags)?),
+        3 => Message::Data {
+            block: u16::from_be_bytes([buffer[2], buffer[3]]),
+            payload: buffer[4..].to_vec(),
+        },
         4 => Message::Ack(u16::from_be_bytes([buffer[2], buffer[3]])),
-        5 => { todo!() },
+        5 => Message::Error {
+            code: ErrorCode::from(u16::from_be_bytes([buffer[2], buffer[3]])),
+            message: extract_strings(&buffer[4..]).swap_remove(0),
+        },
+        6 => Message::OptionAck { options: parse_option_ack(&buffer[2..]) },
         code => { return Err(ParseError::InvalidOpcode(code)) }
     })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Message, TftpOption};
+    use crate::{
+        parse_message, parse_message_with, parse_option, ErrorCode, Message, Mode, NegotiationError, PacketType,
+        ParseError, ParserFlags, TftpOption, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    };
 
     #[test]
     fn encode_oack() {
@@ -284,4 +594,288 @@ mod tests {
         let msg = Message::OptionAck { options };
         eprintln!("{:?}", msg.into_packet());
     }
+
+    #[test]
+    fn parse_rollover() {
+        assert!(matches!(parse_option("rollover", "0", ParserFlags::default()), Some(TftpOption::Rollover(0))));
+        assert!(matches!(parse_option("Rollover", "1", ParserFlags::default()), Some(TftpOption::Rollover(1))));
+        assert!(parse_option("rollover", "2", ParserFlags::default()).is_none());
+    }
+
+    #[test]
+    fn parse_truncated_packet() {
+        assert!(matches!(parse_message(&[0, 1, 0]), Err(ParseError::Truncated(..))));
+        assert!(matches!(parse_message(&[0, 1, b'a', 0]), Err(ParseError::Truncated(..))));
+    }
+
+    #[test]
+    fn parse_missing_fields() {
+        assert!(matches!(parse_message(b"\x00\x01filename"), Err(ParseError::MissingFields(..))));
+    }
+
+    #[test]
+    fn parse_invalid_mode() {
+        assert!(matches!(
+            parse_message(b"\x00\x01filename\x00binary\x00"),
+            Err(ParseError::InvalidString(mode)) if mode == "binary"
+        ));
+    }
+
+    #[test]
+    fn mode_case_and_whitespace() {
+        for request in [&b"\x00\x01filename\x00OCTET\x00"[..], b"\x00\x01filename\x00octet \x00"] {
+            assert!(matches!(parse_message(request), Ok(Message::Read { mode: Mode::Octet, .. })));
+        }
+        assert!(matches!(
+            parse_message(b"\x00\x01filename\x00 binary\x00"),
+            Err(ParseError::InvalidString(_))
+        ));
+    }
+
+    #[test]
+    fn option_before_mode() {
+        assert!(matches!(
+            parse_message(b"\x00\x01filename\x00blksize\x001024\x00octet\x00"),
+            Err(ParseError::InvalidOption(message)) if message.starts_with("blksize")
+        ));
+    }
+
+    #[test]
+    fn parse_invalid_opcode() {
+        assert!(matches!(parse_message(&[0, 9, 0, 0]), Err(ParseError::InvalidOpcode(9))));
+    }
+
+    #[test]
+    fn parse_read_request() {
+        let message = parse_message(b"\x00\x01filename\x00octet\x00blksize\x001024\x00").unwrap();
+        assert!(matches!(
+            message,
+            Message::Read { filename, mode: Mode::Octet, options, ignored }
+                if filename == "filename"
+                    && matches!(options[..], [TftpOption::BlockSize(1024)])
+                    && ignored.is_empty()
+        ));
+    }
+
+    #[test]
+    fn unknown_options_ignored() {
+        let message = parse_message(
+            b"\x00\x01filename\x00octet\x00windowsize\x004\x00blksize\x00512\x00"
+        ).unwrap();
+        assert!(matches!(
+            message,
+            Message::Read { options, ignored, .. }
+                if matches!(options[..], [TftpOption::BlockSize(512)]) && ignored == ["windowsize"]
+        ));
+    }
+
+    #[test]
+    fn block_size_bounds() {
+        assert!(TftpOption::BlockSize(7).validate().is_err());
+        assert!(TftpOption::BlockSize(8).validate().is_ok());
+        assert!(TftpOption::BlockSize(65464).validate().is_ok());
+        assert!(TftpOption::BlockSize(65465).validate().is_err());
+
+        assert!(parse_option("blksize", "7", ParserFlags::default()).is_none());
+        assert!(matches!(parse_option("blksize", "8", ParserFlags::default()), Some(TftpOption::BlockSize(8))));
+        assert!(matches!(parse_option("blksize", "65464", ParserFlags::default()), Some(TftpOption::BlockSize(65464))));
+        assert!(parse_option("blksize", "65465", ParserFlags::default()).is_none());
+    }
+
+    #[test]
+    fn block_size_bounds_in_requests() {
+        for (value, accepted) in [("7", false), ("8", true), ("65464", true), ("65465", false), ("70000", false)] {
+            let request = format!("\x00\x01file\x00octet\x00blksize\x00{value}\x00");
+            let Ok(Message::Read { options, ignored, .. }) = parse_message(request.as_bytes()) else {
+                panic!("request not parsed");
+            };
+            assert_eq!(options.len() == 1, accepted, "blksize={value}");
+            assert_eq!(ignored.is_empty(), accepted, "blksize={value}");
+        }
+        assert_eq!((MIN_BLOCK_SIZE, MAX_BLOCK_SIZE), (8, 65464));
+    }
+
+    #[test]
+    fn micro_timeout() {
+        assert!(matches!(
+            parse_option("utimeout", "250000", ParserFlags::default()),
+            Some(TftpOption::MicroTimeout(250000))
+        ));
+        assert!(parse_option("utimeout", "999", ParserFlags::default()).is_none());
+        assert!(parse_option("utimeout", "255000001", ParserFlags::default()).is_none());
+        assert_eq!(TftpOption::MicroTimeout(250000).encode(), b"utimeout\x00250000\x00");
+    }
+
+    #[test]
+    fn timeout_bounds() {
+        assert!(TftpOption::Timeout(0).validate().is_err());
+        assert!(TftpOption::Timeout(1).validate().is_ok());
+        assert!(TftpOption::Timeout(255).validate().is_ok());
+        assert!(parse_option("timeout", "256", ParserFlags::default()).is_none());
+    }
+
+    #[test]
+    fn classify_packets() {
+        let expected = [
+            PacketType::ReadRequest,
+            PacketType::WriteRequest,
+            PacketType::Data,
+            PacketType::Acknowledgement,
+            PacketType::Error,
+            PacketType::OptionAcknowledgement,
+        ];
+        for (opcode, packet_type) in (1_u8..).zip(expected) {
+            assert_eq!(PacketType::from_packet(&[0, opcode, 0, 1]).unwrap(), packet_type);
+        }
+
+        assert!(matches!(PacketType::from_packet(&[0, 7]), Err(ParseError::InvalidOpcode(7))));
+        assert!(matches!(PacketType::from_packet(&[1, 0]), Err(ParseError::InvalidOpcode(256))));
+        assert!(matches!(PacketType::from_packet(&[0]), Err(ParseError::Truncated(..))));
+    }
+
+    #[test]
+    fn parse_data() {
+        let message = parse_message(b"\x00\x03\x00\x02payload").unwrap();
+        assert!(matches!(message, Message::Data { block: 2, payload } if payload == b"payload"));
+
+        let message = parse_message(b"\x00\x03\x00\x03").unwrap();
+        assert!(matches!(message, Message::Data { block: 3, payload } if payload.is_empty()));
+    }
+
+    #[test]
+    fn data_payload_at_block_boundary() {
+        // The header is not part of the payload, whose length is what
+        // tells the last block apart
+        for len in [511, 512] {
+            let mut packet = vec![0, 3, 0, 1];
+            packet.resize(4 + len, 0xaa);
+            let message = parse_message(&packet).unwrap();
+            assert!(matches!(message, Message::Data { block: 1, payload } if payload.len() == len));
+        }
+    }
+
+    #[test]
+    fn long_mode_rejected() {
+        let mut request = b"\x00\x01file.bin\x00".to_vec();
+        request.resize(request.len() + 10 * 1024, b'o');
+        request.push(0);
+
+        let Err(ParseError::InvalidString(error)) = parse_message(&request) else {
+            panic!("long mode accepted");
+        };
+        assert_eq!(error, "mode too long (10240 bytes)");
+        assert!(parse_message(b"\x00\x01file.bin\x00  NetAscii  \x00").is_ok());
+    }
+
+    #[test]
+    fn parse_error() {
+        let message = parse_message(b"\x00\x05\x00\x01File not found\x00").unwrap();
+        assert!(matches!(
+            message,
+            Message::Error { code: ErrorCode::FileNotFound, message } if message == "File not found"
+        ));
+    }
+
+    #[test]
+    fn unknown_error_codes_preserved() {
+        let packet = b"\x00\x05\x00\x2aCustom\x00";
+        let Ok(Message::Error { code, message }) = parse_message(packet) else {
+            panic!("error not parsed");
+        };
+        assert_eq!(code, ErrorCode::Other(42));
+        assert_eq!(message, "Custom");
+        assert_eq!(code.into_explicit_message("Custom").into_packet(), packet);
+
+        assert_eq!(ErrorCode::Other(42).into_message().into_packet(), b"\x00\x05\x00\x2aUnknown error\x00");
+        assert_eq!(ErrorCode::from(0), ErrorCode::NotDefined);
+    }
+
+    #[test]
+    fn garbage_options_in_strict_mode() {
+        let garbage = b"\x00\x01file\x00octet\x00\xff\xfe\x00blksize\x00huge\x00\x01\x02";
+        let Ok(Message::Read { options, .. }) = parse_message(garbage) else {
+            panic!("request not parsed");
+        };
+        assert!(options.is_empty());
+
+        let flags = ParserFlags { strict_options: true, ..Default::default() };
+        assert!(matches!(parse_message_with(garbage, flags), Err(ParseError::InvalidOption(..))));
+
+        // No options at all, or unknown but well formed ones, are fine
+        assert!(parse_message_with(b"\x00\x01file\x00octet\x00", flags).is_ok());
+        assert!(parse_message_with(b"\x00\x01file\x00octet\x00windowsize\x004\x00", flags).is_ok());
+        assert!(parse_message_with(b"\x00\x01file\x00octet\x00\xff\x00\x00blksize\x001024\x00", flags).is_ok());
+    }
+
+    #[test]
+    fn unknown_options_preserved() {
+        let request = b"\x00\x01file\x00octet\x00windowsize\x004\x00blksize\x001024\x00";
+        let flags = ParserFlags { keep_unknown_options: true, ..Default::default() };
+        let Ok(Message::Read { options, ignored, .. }) = parse_message_with(request, flags) else {
+            panic!("request not parsed");
+        };
+
+        assert!(ignored.is_empty());
+        assert!(matches!(
+            &options[..],
+            [TftpOption::Unknown { name, value }, TftpOption::BlockSize(1024)] if name == "windowsize" && value == "4"
+        ));
+        // Never echoed back
+        assert_eq!(Message::OptionAck { options }.into_packet(), b"\x00\x06blksize\x001024\x00");
+    }
+
+    #[test]
+    fn encode_request_and_ack() {
+        let request = Message::Read {
+            filename: "file".into(),
+            mode: Mode::Octet,
+            options: vec![TftpOption::BlockSize(1024)],
+            ignored: vec!["windowsize".into()],
+        };
+        let packet = request.into_packet();
+        assert_eq!(packet, b"\x00\x01file\x00octet\x00blksize\x001024\x00");
+        assert!(matches!(parse_message(&packet), Ok(Message::Read { filename, .. }) if filename == "file"));
+
+        assert_eq!(Message::Ack(258).into_packet(), [0, 4, 1, 2]);
+    }
+
+    #[test]
+    fn parse_option_ack() {
+        let message = parse_message(b"\x00\x06blksize\x001024\x00tsize\x00300\x00").unwrap();
+        assert!(matches!(
+            message,
+            Message::OptionAck { options }
+                if matches!(options[..], [TftpOption::BlockSize(1024), TftpOption::TransferSize(300)])
+        ));
+    }
+
+    #[test]
+    fn numeric_option_values() {
+        let flags = ParserFlags::default();
+        assert!(matches!(parse_option("blksize", " 1024", flags), Some(TftpOption::BlockSize(1024))));
+        assert!(matches!(parse_option("blksize", "1024 ", flags), Some(TftpOption::BlockSize(1024))));
+        assert!(parse_option("blksize", "+1024", flags).is_none());
+        assert!(parse_option("blksize", "-1024", flags).is_none());
+        assert!(parse_option("blksize", "10 24", flags).is_none());
+        assert!(parse_option("blksize", " ", flags).is_none());
+    }
+
+    #[test]
+    fn validated_option_ack() {
+        let message = Message::option_ack(vec![
+            TftpOption::BlockSize(1024),
+            TftpOption::TransferSize(300),
+            TftpOption::BlockSize(1024),
+        ]).unwrap();
+        assert_eq!(message.into_packet(), b"\x00\x06blksize\x001024\x00tsize\x00300\x00");
+
+        let error = Message::option_ack(vec![TftpOption::BlockSize(1024), TftpOption::BlockSize(512)]);
+        assert!(matches!(error, Err(NegotiationError::Duplicate(name)) if name == "blksize"));
+
+        let error = Message::option_ack(vec![TftpOption::BlockSize(4)]);
+        assert!(matches!(error, Err(NegotiationError::InvalidValue(..))));
+
+        let unknown = TftpOption::Unknown { name: "x".into(), value: "y".into() };
+        assert!(matches!(Message::option_ack(vec![unknown]), Err(NegotiationError::Unknown(..))));
+    }
 }
@@ -1,281 +1,337 @@
-use std::{net::SocketAddr, path::PathBuf};
-
-use clap::{arg, command, value_parser};
-use tokio::{
-    fs::{File, OpenOptions},
-    io::AsyncReadExt,
-    net::UdpSocket,
-    time::{Duration, timeout}
-};
-use anyhow::{bail, Result};
+use std::{io::Read, net::{IpAddr, SocketAddr}, path::PathBuf, time::Duration};
+
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use anyhow::Result;
+use log::LevelFilter;
 
-use tftpd::{parse_message, ErrorCode, Message, Mode, TftpOption};
+use tftpd::{
+    server::{Server, DEFAULT_STATIC_ROOT, MIN_PATH_MTU},
+    source::MemoryProvider,
+    ErrorCode,
+};
 
+const DEFAULT_ADDRESS: &str = "127.0.0.1";
 const DEFAULT_PORT: &str = "69";
-const DEFAULT_STATIC_ROOT: &str = "/srv/tftp/static";
-const BLOCK_SIZE: usize = 512;
-const MAX_ATTEMPTS: usize = 5;
-const DEFAULT_TIMEOUT: u64 = 3000; // milliseconds
+// Access violation, as the server library does by default
+const DEFAULT_WRITE_REJECTION_CODE: &str = "2";
 
-#[derive(Debug)]
 struct Config {
-    port: u16,
-    static_root: PathBuf,
+    server: Server,
+    check_only: bool,
+    // The resolved settings, when they're to be printed instead of served
+    printed_settings: Option<String>,
+    #[cfg(feature = "client")]
+    selftest_only: bool,
+    log_level: LevelFilter,
+    // Flags that were given but do nothing, to warn about once logging
+    // is set up
+    ignored_flags: Vec<&'static str>,
+}
+
+// Warnings are always shown, and every -v adds a level of detail
+fn log_level(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
 }
 
-fn get_config() -> Result<Config> {
-    let matches = command!()
+// Lists the settings given by flags, the environment or defaults as
+// `name = value` lines, named after the options that set them. Options
+// left unset are skipped, and so are the ones that don't configure the
+// server
+fn describe_settings(command: &Command, matches: &ArgMatches) -> String {
+    let mut settings = String::new();
+    for arg in command.get_arguments() {
+        let name = arg.get_id().as_str();
+        if arg.is_hide_set() || matches!(name, "check" | "print-config" | "verbose" | "help" | "version") {
+            continue;
+        }
+        let Some(mut values) = matches.get_raw(name) else {
+            continue;
+        };
+        let value = values.next().unwrap_or_default().to_string_lossy();
+        if value.parse::<bool>().is_ok() || value.parse::<u64>().is_ok() {
+            settings.push_str(&format!("{name} = {value}\n"));
+        } else {
+            settings.push_str(&format!("{name} = {value:?}\n"));
+        }
+    }
+
+    settings
+}
+
+// The address, port and root can also be set through the environment,
+// which is handy for containers. Flags take precedence
+fn build_command() -> Command {
+    let command = command!()
+        .arg(arg!(-a --address <ADDRESS> "Listening address")
+                .value_parser(value_parser!(IpAddr))
+                .env("TFTPD_ADDRESS")
+                .default_value(DEFAULT_ADDRESS))
         .arg(arg!(-p --port <PORT> "Listening port")
                 .value_parser(value_parser!(u16))
+                .env("TFTPD_PORT")
                 .default_value(DEFAULT_PORT))
         .arg(arg!(-r --root <ROOT> "Root directory containing files to be served")
                 .value_parser(value_parser!(PathBuf))
+                .env("TFTPD_ROOT")
                 .default_value(DEFAULT_STATIC_ROOT))
-        .get_matches();
+        .arg(arg!(--"path-mtu" <MTU> "Assumed path MTU, used to limit the negotiated block size")
+                .value_parser(value_parser!(u16).range(i64::from(MIN_PATH_MTU)..)))
+        .arg(arg!(--"max-file-size" <BYTES> "Refuse to serve files larger than this")
+                .value_parser(value_parser!(u64)))
+        .arg(arg!(--"max-tsize" <BYTES> "Refuse requests declaring a transfer size larger than this")
+                .value_parser(value_parser!(u64)))
+        .arg(arg!(--"max-timeout" <SECONDS> "Lower the timeouts asked for by clients to this")
+                .value_parser(value_parser!(u8).range(1..)))
+        .arg(arg!(--"cache-size" <FILES> "Keep the contents of up to this many files in memory")
+                .value_parser(value_parser!(usize)))
+        .arg(arg!(--"metrics-addr" <ADDRESS> "Export metrics over HTTP on this address")
+                .value_parser(value_parser!(SocketAddr)))
+        .arg(arg!(--"case-insensitive" "Fall back to a case-insensitive lookup for missing files"))
+        .arg(arg!(--"enable-listing" "Serve a listing of the contents when a directory is requested"))
+        // Dotfiles are hidden unless --allow-dotfiles is given, so this
+        // one does nothing, and is only kept for existing setups
+        .arg(arg!(--"no-dotfiles" "Act as if files and directories starting with a dot didn't exist (the default)")
+                .hide(true))
+        .arg(arg!(--"allow-dotfiles" "Serve files and directories starting with a dot")
+                .conflicts_with("no-dotfiles"))
+        .arg(arg!(--"serve-stdin" "Read all of stdin at startup and serve it for any requested file"))
+        .arg(arg!(--"auto-decompress" "Serve the decompressed contents of FILE.gz when FILE is missing"))
+        .arg(arg!(--"single-port" "Carry the transfers over the listening port instead of a new one for each"))
+        .arg(arg!(--"reuse-port" "Let other processes bind the same port (SO_REUSEPORT) to share the load"))
+        .arg(arg!(--"roaming-clients" "Keep transfers going when the client's port changes midway"))
+        .arg(arg!(--"write-rejection" <CODE> "Error code answering write requests")
+                .value_parser(value_parser!(u16).range(0..=8))
+                .default_value(DEFAULT_WRITE_REJECTION_CODE))
+        .arg(arg!(--"oack-fallback" "Carry on with a plain transfer if the client never acknowledges the options"))
+        .arg(arg!(--"strict-options" "Refuse requests whose options are all unusable, instead of ignoring them"))
+        .arg(arg!(--"no-options" "Ignore the options requested by clients"))
+        .arg(arg!(--"global-rate" <TRANSFERS> "Accept at most this many new transfers per second")
+                .value_parser(value_parser!(u32).range(1..)))
+        .arg(arg!(--"fair-scheduling" <SENDS> "Make transfers take turns to send, with up to this many sending at once")
+                .value_parser(value_parser!(u32).range(1..)))
+        .arg(arg!(--"max-lifetime" <SECONDS> "Cancel transfers still running after this long")
+                .value_parser(value_parser!(u64).range(1..)))
+        .arg(arg!(--"trace-file" <PATH> "Append a dump of every packet sent or received to this file")
+                .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(-v --verbose "Log more details, can be repeated").action(ArgAction::Count))
+        .arg(arg!(--check "Validate the configuration and exit"))
+        .arg(arg!(--"print-config" "Print the configuration resulting from flags, environment and defaults, and exit"));
+    #[cfg(unix)]
+    let command = command.arg(arg!(--chroot "Confine the server to the root directory after binding"));
+    #[cfg(feature = "client")]
+    let command = command.subcommand(
+        Command::new("selftest").about("Download a generated file from a server on a loopback port, and exit")
+    );
+
+    command
+}
 
+fn get_config(matches: ArgMatches) -> Result<Config> {
+    let address = *matches.get_one::<IpAddr>("address").unwrap();
     let port = *matches.get_one::<u16>("port").unwrap();
     let static_root = matches.get_one::<PathBuf>("root").unwrap().to_owned();
-
-    Ok(Config {
-        port,
-        static_root,
-    })
-}
-
-async fn open_file(config: &Config, filename: &str) -> Result<File, Message> {
-    let mut path = config.static_root.clone();
-    path.push(filename);
-    // Verify that appending the filename hasn't directed out of the
-    // filesystem root (can happen when the path is normalized, for
-    // example suplying relative paths)
-    if !path.starts_with(&config.static_root) {
-        return Err(ErrorCode::AccessViolation.into_explicit_message("Illegal path"));
+    let path_mtu = matches.get_one::<u16>("path-mtu").copied();
+    let max_file_size = matches.get_one::<u64>("max-file-size").copied();
+    let cache_size = matches.get_one::<usize>("cache-size").copied();
+    let metrics_address = matches.get_one::<SocketAddr>("metrics-addr").copied();
+    let trace_file = matches.get_one::<PathBuf>("trace-file").cloned();
+    let max_lifetime = matches.get_one::<u64>("max-lifetime").copied().map(Duration::from_secs);
+
+    let mut server = Server::builder();
+    if matches.get_flag("serve-stdin") {
+        let mut contents = vec![];
+        std::io::stdin().read_to_end(&mut contents)?;
+        server = server.virtual_files(MemoryProvider::new().fallback(contents));
     }
 
-    Ok(match OpenOptions::new().read(true).open(path).await {
-        Ok(file) => file,
-        Err(error) => {
-            return Err(match error.kind() {
-                std::io::ErrorKind::NotFound => {
-                    ErrorCode::FileNotFound.into_message()
-                }
-                std::io::ErrorKind::PermissionDenied => {
-                    ErrorCode::AccessViolation.into_explicit_message("Permission denied")
-                }
-                _ => ErrorCode::NotDefined.into_explicit_message(&format!("{error}")),
-            })
-        }
+    let server = server
+        .address(address)
+        .port(port)
+        .root(static_root)
+        .path_mtu(path_mtu)
+        .max_file_size(max_file_size)
+        .max_tsize(matches.get_one::<u64>("max-tsize").copied())
+        .max_timeout(matches.get_one::<u8>("max-timeout").copied())
+        .file_cache(cache_size)
+        .metrics_address(metrics_address)
+        .case_insensitive(matches.get_flag("case-insensitive"))
+        .enable_listing(matches.get_flag("enable-listing"))
+        .hide_dotfiles(!matches.get_flag("allow-dotfiles"))
+        .auto_decompress(matches.get_flag("auto-decompress"))
+        .single_port(matches.get_flag("single-port"))
+        .reuse_port(matches.get_flag("reuse-port"))
+        .roaming_clients(matches.get_flag("roaming-clients"))
+        .write_rejection(ErrorCode::from(*matches.get_one::<u16>("write-rejection").unwrap()))
+        .oack_fallback(matches.get_flag("oack-fallback"))
+        .no_options(matches.get_flag("no-options"))
+        .strict_options(matches.get_flag("strict-options"))
+        .max_lifetime(max_lifetime)
+        .global_rate(matches.get_one::<u32>("global-rate").copied())
+        .fair_scheduling(matches.get_one::<u32>("fair-scheduling").map(|&sends| sends as usize))
+        .trace_file(trace_file);
+    #[cfg(unix)]
+    let server = server.chroot(matches.get_flag("chroot"));
+    let server = server.build();
+
+    Ok(Config {
+        server,
+        check_only: matches.get_flag("check"),
+        printed_settings: matches.get_flag("print-config").then(|| describe_settings(&build_command(), &matches)),
+        #[cfg(feature = "client")]
+        selftest_only: matches.subcommand_name() == Some("selftest"),
+        log_level: log_level(matches.get_count("verbose")),
+        ignored_flags: ["no-dotfiles"].into_iter().filter(|&flag| matches.get_flag(flag)).collect(),
     })
 }
 
-async fn read_block(file: &mut File, block_size: usize) -> Result<Vec<u8>> {
-    let mut buffer = vec![0; block_size];
-    let len = file.read(&mut buffer).await?;
-
-    Ok(buffer[..len].to_vec())
+// Serves a generated file from an ephemeral loopback port, and downloads
+// it through the client, checking that it arrives intact. The size is
+// not a multiple of the block size, so that the transfer ends with a
+// short block
+#[cfg(feature = "client")]
+async fn selftest() -> Result<u64> {
+    use tftpd::{client::Client, TftpOption};
+
+    let contents = (0..100_000).map(|n| (n % 251) as u8).collect::<Vec<_>>();
+    let listener = Server::builder()
+        .port(0)
+        .virtual_files(MemoryProvider::new().file("selftest.bin", contents.clone()))
+        .build()
+        .bind()
+        .await?;
+    let server_addr = listener.local_addr()?;
+    let server = tokio::spawn(listener.run());
+
+    let mut received = vec![];
+    let result = Client::new(server_addr)
+        .option(TftpOption::BlockSize(1024))
+        .option(TftpOption::TransferSize(0))
+        .get("selftest.bin", &mut received)
+        .await;
+    server.abort();
+
+    let size = result?;
+    anyhow::ensure!(received == contents, "Downloaded file differs from the one served");
+
+    Ok(size)
 }
 
-enum Dest {
-    Fixed,
-    Addr(SocketAddr),
-}
+// Every SIGUSR1 flips drain mode on or off
+#[cfg(unix)]
+async fn toggle_drain_on_signal(draining: std::sync::Arc<std::sync::atomic::AtomicBool>) {
+    use std::sync::atomic::Ordering;
+    use tokio::signal::unix::{signal, SignalKind};
 
-async fn send_error(sock: &UdpSocket, msg: Message, to: Dest) {
-    let packet = msg.into_packet();
-    let res = match to {
-        Dest::Fixed => sock.send(&packet).await,
-        Dest::Addr(addr) => sock.send_to(&packet, addr).await,
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(error) => {
+            log::warn!("Drain mode not available: {error}");
+            return;
+        }
     };
-
-    match res {
-        Err(error) => eprintln!("While trying to send an error message: {error:?}"),
-        _ => {}
-    }
-}
-
-fn get_block_size(options: &[TftpOption]) -> usize {
-    for opt in options {
-        match opt {
-            TftpOption::BlockSize(bls) => { return *bls as usize },
-            _ => {}
+    while signals.recv().await.is_some() {
+        if draining.fetch_xor(true, Ordering::Relaxed) {
+            log::warn!("Leaving drain mode, accepting new transfers");
+        } else {
+            log::warn!("Entering drain mode, turning down new transfers");
         }
     }
-
-    BLOCK_SIZE
 }
 
-fn get_timeout(options: &[TftpOption]) -> u64 {
-    for opt in options {
-        match opt {
-            TftpOption::Timeout(tout) => { return *tout as u64 },
-            _ => {}
-        }
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = get_config(build_command().get_matches())?;
+    // RUST_LOG still takes precedence, for finer grained filtering
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .init();
+    for flag in &config.ignored_flags {
+        log::warn!("--{flag} does nothing, as it's already the default, and will be removed");
     }
 
-    DEFAULT_TIMEOUT
-}
-
-fn get_transfer_size(options: &[TftpOption]) -> Option<u64> {
-    for opt in options {
-        match opt {
-            TftpOption::TransferSize(tsize) => { return Some(*tsize) },
-            _ => {}
-        }
+    #[cfg(feature = "client")]
+    if config.selftest_only {
+        let size = selftest().await?;
+        println!("Self-test OK, {size} bytes transferred");
+        return Ok(());
     }
 
-    None
-}
-
-async fn packet_and_ack(sock: &UdpSocket, block: u16, packet: &Vec<u8>, block_size: usize, tout: Duration) -> Result<()> {
-    let mut read_buffer = vec![0; block_size];
-    let mut failed_attempts = 0;
-    let mut waiting_for_ack = false;
-    while failed_attempts < MAX_ATTEMPTS {
-        if !waiting_for_ack {
-            if !sock.send(&packet).await.is_ok() {
-                // Abort, something really wrong happened here
-                bail!("Critical error attemting to send packet");
-            }
-            waiting_for_ack = true;
-        } else if timeout(tout, sock.recv(&mut read_buffer)).await.is_ok() {
-            if let Ok(message) = parse_message(&read_buffer) {
-                match message {
-                    Message::Ack(block_id) => {
-                        if block_id == block {
-                            break;
-                        }
-                    }
-                    _ => {
-                        send_error(
-                            &sock,
-                            ErrorCode::IllegalOperation.into_message(),
-                            Dest::Fixed
-                            ).await;
-                    }
-                };
-            }
-        } else {
-            failed_attempts += 1;
-            eprintln!("Timeout (failed: {failed_attempts}/{MAX_ATTEMPTS})");
-            waiting_for_ack = false;
-        }
+    if let Some(settings) = config.printed_settings {
+        print!("{settings}");
+        return Ok(());
     }
 
-    if failed_attempts >= MAX_ATTEMPTS {
-        bail!("Too many retries")
+    if config.check_only {
+        config.server.check().await?;
+        println!("Configuration OK");
+        return Ok(());
     }
 
+    let listener = config.server.bind().await?;
+    #[cfg(unix)]
+    tokio::spawn(toggle_drain_on_signal(listener.draining()));
+    listener.run_until(async {
+        let _ = tokio::signal::ctrl_c().await;
+    }).await?;
+
     Ok(())
 }
 
-async fn worker_task(sock: UdpSocket, mut file: File, options: Vec<TftpOption>) {
-    let block_size = get_block_size(&options);
-    let tout = Duration::from_millis(get_timeout(&options));
-
-    if options.len() > 0 {
-        if let Some(tsize) = get_transfer_size(&options) {
-            let fsize = file.metadata().await.unwrap().len();
-
-            if tsize > fsize {
-                send_error(
-                    &sock,
-                    ErrorCode::OptionNegotiationError
-                        .into_explicit_message("File too large"),
-                    Dest::Fixed).await;
-                return;
-            }
-        }
-
-        let msg = Message::OptionAck { options }.into_packet();
-        match packet_and_ack(&sock, 0, &msg, block_size, tout).await {
-            Err(error) => eprintln!("{error}"),
-            _ => {}
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_levels() {
+        assert_eq!(log_level(0), LevelFilter::Warn);
+        assert_eq!(log_level(1), LevelFilter::Info);
+        assert_eq!(log_level(2), LevelFilter::Debug);
+        assert_eq!(log_level(3), LevelFilter::Trace);
+        assert_eq!(log_level(10), LevelFilter::Trace);
     }
 
-    let mut current_block: u16 = 0;
-    loop {
-        current_block += 1;
-        let payload = match read_block(&mut file, block_size).await {
-            Ok(data) => data,
-            Err(_) => {
-                send_error(&sock, ErrorCode::NotDefined.into_message(), Dest::Fixed).await;
-                break;
-            }
-        };
-        let payload_len = payload.len();
-
-        let message = Message::Data { block: current_block, payload }.into_packet();
-
-        match packet_and_ack(&sock, current_block, &message, block_size, tout).await {
-            Err(error) => eprintln!("{error}"),
-            _ => {}
-        }
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn selftest_passes() {
+        assert_eq!(selftest().await.unwrap(), 100_000);
+    }
 
-        if payload_len < block_size {
-            break;
-        }
+    #[test]
+    fn printed_configuration() {
+        let matches = build_command()
+            .try_get_matches_from(["tftpd", "--port", "6970", "--root", "/srv/boot", "--print-config"])
+            .unwrap();
+        let printed = get_config(matches).unwrap().printed_settings.unwrap();
+        assert!(printed.lines().any(|line| line == "port = 6970"));
+        assert!(printed.lines().any(|line| line == "root = \"/srv/boot\""));
+        assert!(printed.lines().any(|line| line == "allow-dotfiles = false"));
+        assert!(!printed.lines().any(|line| line.starts_with("print-config") || line.starts_with("cache-size")));
+
+        let matches = build_command().try_get_matches_from(["tftpd"]).unwrap();
+        assert!(get_config(matches).unwrap().printed_settings.is_none());
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let config = get_config()?;
-
-    let sock = UdpSocket::bind(("127.0.0.1", config.port)).await?;
-
-    let mut buf = [0; 1024];
-    loop {
-        let (_, addr) = sock.recv_from(&mut buf).await?;
-
-        match parse_message(&buf) {
-            Ok(message) => {
-                match message {
-                    Message::Write { .. } => {
-                        sock.send_to(
-                            ErrorCode::IllegalOperation
-                                .into_explicit_message("No write permission")
-                                .into_packet().as_ref(),
-                            addr).await?;
-                    }
-                    Message::Read { filename, mode, options } => {
-                        if mode != Mode::Octet {
-                            send_error(
-                                &sock, 
-                                ErrorCode::IllegalOperation
-                                    .into_explicit_message("Only Octet transfers are supported"),
-                                Dest::Addr(addr),
-                                ).await
-                        } else {
-                            match open_file(&config, &filename).await {
-                                Ok(file) => {
-                                    // TODO: We should look for errors here...
-                                    let sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
-                                    sock.connect(addr).await.unwrap();
-
-                                    tokio::spawn(worker_task(sock, file, options));
-                                }
-                                Err(errmsg) => {
-                                    send_error(&sock, errmsg, Dest::Addr(addr)).await;
-                                }
-                            }
-                        }
-                    }
-                    msg => {
-                        if !sock.send_to(
-                            &ErrorCode::IllegalOperation
-                                .into_message()
-                                .into_packet(),
-                            addr).await.is_ok()
-                        {
-                            eprintln!("Error trying to answer to illegal message: {msg:?}");
-                        }
-                    }
-                }
-            },
-            Err(error) => {
-                eprintln!("While parsing message: {error}");
-            },
+    #[test]
+    fn dotfiles_flags() {
+        for (args, hidden, ignored) in [
+            (&[][..], true, &[][..]),
+            (&["--no-dotfiles"], true, &["no-dotfiles"]),
+            (&["--allow-dotfiles"], false, &[]),
+        ] {
+            let matches = build_command().try_get_matches_from(["tftpd"].iter().chain(args)).unwrap();
+            let config = get_config(matches).unwrap();
+            let printed = format!("{:#?}", config.server);
+            assert!(printed.contains(&format!("hide_dotfiles: {hidden},")), "{args:?}");
+            assert_eq!(config.ignored_flags, ignored, "{args:?}");
         }
+        assert!(!build_command().render_help().to_string().contains("--no-dotfiles"));
+
+        assert!(build_command().try_get_matches_from(["tftpd", "--no-dotfiles", "--allow-dotfiles"]).is_err());
     }
 }
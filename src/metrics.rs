@@ -0,0 +1,203 @@
+use std::{
+    fmt::Write,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::ErrorCode;
+
+// Error codes defined by RFC 1350 and RFC 2347
+const ERROR_CODES: usize = 9;
+
+// Scrapers send short requests right away. Anything else is dropped, so
+// that idle or bogus connections don't pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Counters describing the activity of the server, updated by the
+/// transfers as they progress
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_transfers: AtomicU64,
+    transfers: AtomicU64,
+    bytes_sent: AtomicU64,
+    errors: [AtomicU64; ERROR_CODES],
+}
+
+/// Accounts for a transfer as active for as long as it's alive
+pub struct ActiveTransfer<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveTransfer<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_transfers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn transfer_started(&self) -> ActiveTransfer<'_> {
+        self.transfers.fetch_add(1, Ordering::Relaxed);
+        self.active_transfers.fetch_add(1, Ordering::Relaxed);
+        ActiveTransfer { metrics: self }
+    }
+
+    // Like any other counter, it wraps around on overflow
+    pub fn bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn error_sent(&self, code: ErrorCode) {
+        if let Some(counter) = self.errors.get(usize::from(u16::from(code))) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn active_transfers(&self) -> u64 {
+        self.active_transfers.load(Ordering::Relaxed)
+    }
+
+    pub fn total_transfers(&self) -> u64 {
+        self.transfers.load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self, code: ErrorCode) -> u64 {
+        self.errors.get(usize::from(u16::from(code))).map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Renders the counters using the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        writeln!(output, "tftpd_active_transfers {}", self.active_transfers()).unwrap();
+        writeln!(output, "tftpd_transfers_total {}", self.total_transfers()).unwrap();
+        writeln!(output, "tftpd_bytes_sent_total {}", self.total_bytes_sent()).unwrap();
+        for (code, counter) in self.errors.iter().enumerate() {
+            let count = counter.load(Ordering::Relaxed);
+            writeln!(output, "tftpd_errors_total{{code=\"{code}\"}} {count}").unwrap();
+        }
+
+        output
+    }
+
+    /// Answers any HTTP request with the current counters. Not meant to
+    /// be a general purpose HTTP server, just enough for scrapers
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                // The request itself is irrelevant, but it has to be
+                // consumed before answering
+                match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => {
+                        log::debug!("While reading a metrics request: {error}");
+                        return;
+                    }
+                    Err(_) => {
+                        log::debug!("Metrics request timed out");
+                        return;
+                    }
+                }
+                let body = metrics.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(error) = stream.write_all(response.as_bytes()).await {
+                    log::warn!("While answering a metrics request: {error}");
+                }
+            });
+        }
+    }
+}
+
+// Reads up to the end of the request headers
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "Request too large"));
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metrics_over_http() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.bytes_sent(1234);
+        metrics.error_sent(ErrorCode::FileNotFound);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics.clone().serve(listener));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("tftpd_bytes_sent_total 1234\n"));
+        assert!(response.contains("tftpd_errors_total{code=\"1\"} 1\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bad_requests_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(Metrics::default()).serve(listener));
+
+        // Never finishes sending its request
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[b'a'; 2 * MAX_REQUEST_SIZE]).await.unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn active_transfers_tracked() {
+        let metrics = Metrics::default();
+        let transfer = metrics.transfer_started();
+        assert_eq!(metrics.active_transfers(), 1);
+        drop(transfer);
+        assert_eq!(metrics.active_transfers(), 0);
+        assert_eq!(metrics.total_transfers(), 1);
+    }
+}
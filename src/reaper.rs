@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    sync::Notify,
+    time::{Duration, Instant},
+};
+
+// How often the registry is checked for transfers past their lifetime
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of the transfers in progress, so that they can be counted,
+/// waited for, or cancelled when running for longer than allowed, whatever
+/// the state they're in
+#[derive(Default)]
+pub struct WorkerRegistry {
+    next_id: AtomicU64,
+    workers: Mutex<HashMap<u64, (Instant, Arc<Notify>)>>,
+    idle: Notify,
+}
+
+impl std::fmt::Debug for WorkerRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerRegistry").field("workers", &self.len()).finish()
+    }
+}
+
+/// Registration of a single transfer, which is removed from the registry
+/// when dropped
+pub struct WorkerHandle {
+    registry: Arc<WorkerRegistry>,
+    id: u64,
+    cancel: Arc<Notify>,
+}
+
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        let mut workers = self.registry.workers.lock().unwrap();
+        workers.remove(&self.id);
+        if workers.is_empty() {
+            self.registry.idle.notify_waiters();
+        }
+    }
+}
+
+impl WorkerHandle {
+    /// Runs `worker` until it finishes or the reaper cancels it. Returns
+    /// false in the latter case
+    pub async fn supervise(self, worker: impl Future<Output = ()>) -> bool {
+        tokio::select! {
+            _ = worker => true,
+            _ = self.cancel.notified() => false,
+        }
+    }
+}
+
+impl WorkerRegistry {
+    pub fn register(self: &Arc<Self>) -> WorkerHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(Notify::new());
+        self.workers.lock().unwrap().insert(id, (Instant::now(), cancel.clone()));
+
+        WorkerHandle { registry: self.clone(), id, cancel }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until there are no workers left
+    pub async fn wait_idle(&self) {
+        loop {
+            // Created before checking, so that no notification is missed
+            let idle = self.idle.notified();
+            if self.is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Cancels the workers started more than `max_lifetime` ago,
+    /// returning how many of them were
+    pub fn reap(&self, max_lifetime: Duration) -> usize {
+        let now = Instant::now();
+        let workers = self.workers.lock().unwrap();
+        let mut reaped = 0;
+        for (started, cancel) in workers.values() {
+            if now.duration_since(*started) > max_lifetime {
+                // Stores a permit if the worker is not waiting yet
+                cancel.notify_one();
+                reaped += 1;
+            }
+        }
+
+        reaped
+    }
+
+    /// Periodically reaps the workers over `max_lifetime`. Never returns
+    pub async fn run_reaper(self: Arc<Self>, max_lifetime: Duration) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL.min(max_lifetime));
+        loop {
+            interval.tick().await;
+            let reaped = self.reap(max_lifetime);
+            if reaped > 0 {
+                log::warn!("Cancelled {reaped} transfer(s) over the maximum lifetime");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn finished_workers_leave_the_registry() {
+        let registry = Arc::new(WorkerRegistry::default());
+        let handle = registry.register();
+        assert_eq!(registry.len(), 1);
+
+        assert!(handle.supervise(async {}).await);
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn active_workers_counted() {
+        let registry = Arc::new(WorkerRegistry::default());
+        let (finish_first, first_finished) = tokio::sync::oneshot::channel::<()>();
+        let (finish_second, second_finished) = tokio::sync::oneshot::channel::<()>();
+
+        let first = registry.register();
+        let first = tokio::spawn(first.supervise(async { let _ = first_finished.await; }));
+        let second = registry.register();
+        tokio::spawn(second.supervise(async { let _ = second_finished.await; }));
+        assert_eq!(registry.len(), 2);
+
+        finish_first.send(()).unwrap();
+        assert!(first.await.unwrap());
+        assert_eq!(registry.len(), 1);
+
+        let idle = registry.wait_idle();
+        finish_second.send(()).unwrap();
+        idle.await;
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Queue where transfers wait for their turn to send a packet. Turns
+/// are handed out in the order they were asked for, so that a transfer
+/// sending its next block has to wait behind every other one already
+/// waiting, and large transfers can't hog the network
+#[derive(Debug)]
+pub struct SendQueue {
+    turns: Semaphore,
+}
+
+impl SendQueue {
+    /// Allows up to `concurrent` packets to be sent at once
+    pub fn new(concurrent: usize) -> Self {
+        SendQueue { turns: Semaphore::new(concurrent.max(1)) }
+    }
+
+    /// Waits for a turn to send, which lasts until the permit is dropped
+    pub async fn turn(&self) -> SemaphorePermit<'_> {
+        // The semaphore is never closed
+        self.turns.acquire().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn turns_taken_in_order() {
+        let queue = Arc::new(SendQueue::new(1));
+        let sent = Arc::new(Mutex::new(vec![]));
+
+        // A large transfer gets going before a small one shows up
+        let first_turn = queue.turn().await;
+        let transfers = [("large", 6), ("small", 2)].map(|(name, blocks)| {
+            let (queue, sent) = (queue.clone(), sent.clone());
+            tokio::spawn(async move {
+                for _ in 0..blocks {
+                    let _turn = queue.turn().await;
+                    sent.lock().unwrap().push(name);
+                    tokio::task::yield_now().await;
+                }
+            })
+        });
+        tokio::task::yield_now().await;
+        drop(first_turn);
+        for transfer in transfers {
+            transfer.await.unwrap();
+        }
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["large", "small", "large", "small", "large", "large", "large", "large"]
+        );
+    }
+}
@@ -0,0 +1,3271 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, Interest},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+    time::{Duration, Instant, timeout_at}
+};
+use anyhow::{bail, Result};
+
+use crate::{
+    cache::FileCache,
+    metrics::Metrics,
+    parse_message, parse_message_with,
+    ratelimit::TokenBucket,
+    reaper::WorkerRegistry,
+    schedule::SendQueue,
+    source::{FileSource, GzipSource, MemoryProvider, TransferSource},
+    trace::{Direction, Trace},
+    check_options, ErrorCode, Message, Mode, PacketType, ParseError, ParserFlags, TftpOption, MAX_REQUEST_SIZE,
+};
+
+pub const DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 69;
+pub const DEFAULT_STATIC_ROOT: &str = "/srv/tftp/static";
+const BLOCK_SIZE: usize = 512;
+const MAX_ATTEMPTS: usize = 5;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+// Upper limit for the retransmission timeout, as it doubles after
+// each failed attempt
+const MAX_BACKOFF_TIMEOUT: Duration = Duration::from_secs(16);
+// Pessimistic link speed, in bytes per second, used to estimate how much
+// longer large blocks take to go through (1 Mbit/s)
+const ASSUMED_LINK_RATE: f64 = 125_000.0;
+// IPv4 (20) + UDP (8) + TFTP DATA (4) headers
+const DATA_OVERHEAD: u16 = 32;
+/// Error answering write requests, as uploads are not supported. Most
+/// servers use an access violation for this, which clients take as a
+/// permanent refusal
+pub const DEFAULT_WRITE_REJECTION: ErrorCode = ErrorCode::AccessViolation;
+/// Smallest path MTU that still allows for the minimum block size
+pub const MIN_PATH_MTU: u16 = DATA_OVERHEAD + 8;
+// Largest packet expected from a client during a transfer. Those are
+// ACKs and errors, or a repeated request at most
+const MAX_CONTROL_SIZE: usize = MAX_REQUEST_SIZE;
+// Opcode and block number, nothing else
+const ACK_SIZE: usize = 4;
+// Packets waiting for a worker in single port mode
+const TRANSFER_QUEUE: usize = 16;
+
+#[derive(Debug)]
+struct Config {
+    address: IpAddr,
+    port: u16,
+    static_root: PathBuf,
+    virtual_files: MemoryProvider,
+    path_mtu: Option<u16>,
+    max_file_size: Option<u64>,
+    max_tsize: Option<u64>,
+    max_timeout: Option<u8>,
+    file_cache: Option<FileCache>,
+    metrics_address: Option<SocketAddr>,
+    trace_file: Option<PathBuf>,
+    #[cfg(unix)]
+    chroot: bool,
+    case_insensitive: bool,
+    enable_listing: bool,
+    hide_dotfiles: bool,
+    strict_options: bool,
+    auto_decompress: bool,
+    single_port: bool,
+    reuse_port: bool,
+    roaming_clients: bool,
+    traversal: TraversalPolicy,
+    write_rejection: ErrorCode,
+    authorizer: Authorizer,
+    resolver: Resolver,
+    max_lifetime: Option<Duration>,
+    global_rate: Option<u32>,
+    workers: Arc<WorkerRegistry>,
+    draining: Arc<AtomicBool>,
+    transfer: TransferSettings,
+}
+
+impl Config {
+    // Virtual files (which include stdin contents) can be served without
+    // a root directory
+    fn root_required(&self) -> bool {
+        self.virtual_files.is_empty()
+    }
+}
+
+type AuthorizeHook = dyn Fn(SocketAddr, String) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync;
+
+// Decides which requests get served, allowing all of them by default
+#[derive(Clone, Default)]
+struct Authorizer(Option<Arc<AuthorizeHook>>);
+
+impl Authorizer {
+    async fn allows(&self, peer: SocketAddr, filename: &str) -> bool {
+        match &self.0 {
+            Some(hook) => hook(peer, filename.into()).await,
+            None => true,
+        }
+    }
+}
+
+impl std::fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Authorizer").field(&self.0.is_some()).finish()
+    }
+}
+
+type ResolveHook = dyn Fn(SocketAddr, &str) -> Option<String> + Send + Sync;
+
+// Picks a client specific file to serve in place of the requested one
+#[derive(Clone, Default)]
+struct Resolver(Option<Arc<ResolveHook>>);
+
+impl Resolver {
+    fn resolve(&self, peer: SocketAddr, filename: &str) -> Option<String> {
+        self.0.as_ref().and_then(|hook| hook(peer, filename))
+    }
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Resolver").field(&self.0.is_some()).finish()
+    }
+}
+
+/// Summary of a transfer, handed to the completion hook once it ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferStats {
+    pub client: Option<SocketAddr>,
+    pub filename: String,
+    /// Bytes sent and acknowledged by the client
+    pub bytes: u64,
+    /// Whether the whole file made it to the client
+    pub success: bool,
+    pub duration: Duration,
+}
+
+type CompletionHook = dyn Fn(TransferStats) + Send + Sync;
+
+// Told about every transfer once it ends, if there's a hook
+#[derive(Clone, Default)]
+struct Completion(Option<Arc<CompletionHook>>);
+
+impl Completion {
+    fn report(&self, client: Option<SocketAddr>, filename: &str) -> CompletionReport {
+        CompletionReport {
+            hook: self.0.clone(),
+            started: Instant::now(),
+            stats: TransferStats {
+                client,
+                filename: filename.into(),
+                bytes: 0,
+                success: false,
+                duration: Duration::ZERO,
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for Completion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Completion").field(&self.0.is_some()).finish()
+    }
+}
+
+// Calls the completion hook when dropped, so that transfers cancelled
+// halfway are reported too
+struct CompletionReport {
+    hook: Option<Arc<CompletionHook>>,
+    started: Instant,
+    stats: TransferStats,
+}
+
+impl Drop for CompletionReport {
+    fn drop(&mut self) {
+        if let Some(hook) = &self.hook {
+            let mut stats = self.stats.clone();
+            stats.duration = self.started.elapsed();
+            hook(stats);
+        }
+    }
+}
+
+/// Progress of a transfer, sent out periodically while it runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferProgress {
+    pub client: Option<SocketAddr>,
+    pub filename: String,
+    /// Bytes sent and acknowledged so far
+    pub bytes: u64,
+    /// Size of the whole file, if known
+    pub size: Option<u64>,
+}
+
+impl TransferProgress {
+    pub fn percent(&self) -> Option<f64> {
+        match self.size {
+            Some(0) => Some(100.0),
+            Some(size) => Some(self.bytes as f64 * 100.0 / size as f64),
+            None => None,
+        }
+    }
+}
+
+// Where progress events go, and how often. Events are dropped rather
+// than holding back the transfer when the receiver falls behind
+#[derive(Debug, Clone)]
+struct Progress {
+    events: mpsc::Sender<TransferProgress>,
+    interval: Duration,
+}
+
+// Settings that apply to every transfer, handed over to the workers
+#[derive(Debug, Clone, Default)]
+struct TransferSettings {
+    metrics: Arc<Metrics>,
+    trace: Option<Arc<Trace>>,
+    oack_fallback: bool,
+    no_options: bool,
+    sends: Option<Arc<SendQueue>>,
+    completion: Completion,
+    progress: Option<Progress>,
+}
+
+impl TransferSettings {
+    // Records a packet in the trace file, if there's one. The peer is
+    // only needed for sockets that are not connected
+    fn trace(&self, direction: Direction, sock: &UdpSocket, peer: Option<SocketAddr>, packet: &[u8]) {
+        if let Some(trace) = &self.trace {
+            if let Some(peer) = peer.or_else(|| sock.peer_addr().ok()) {
+                trace.record(direction, peer, packet);
+            }
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    config: Config,
+}
+
+impl ServerBuilder {
+    /// Listening address
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.config.address = address;
+        self
+    }
+
+    /// Listening port
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Root directory containing the files to be served
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.config.static_root = root.into();
+        self
+    }
+
+    /// Files served from memory. They take precedence over the ones
+    /// found under the root directory
+    pub fn virtual_files(mut self, provider: MemoryProvider) -> Self {
+        self.config.virtual_files = provider;
+        self
+    }
+
+    /// Assumed path MTU, used to limit the negotiated block size
+    pub fn path_mtu(mut self, mtu: Option<u16>) -> Self {
+        self.config.path_mtu = mtu.map(|mtu| mtu.max(MIN_PATH_MTU));
+        self
+    }
+
+    /// Largest file that will be served, in bytes
+    pub fn max_file_size(mut self, size: Option<u64>) -> Self {
+        self.config.max_file_size = size;
+        self
+    }
+
+    /// Largest transfer size a client may declare through the tsize
+    /// option. Requests declaring more are refused
+    pub fn max_tsize(mut self, size: Option<u64>) -> Self {
+        self.config.max_tsize = size;
+        self
+    }
+
+    /// Longest timeout a client may ask for, in seconds. Longer ones are
+    /// lowered to it in the OACK, which RFC 2349 doesn't foresee, so
+    /// strict clients may refuse to go on
+    pub fn max_timeout(mut self, timeout: Option<u8>) -> Self {
+        self.config.max_timeout = timeout;
+        self
+    }
+
+    /// Keep the contents of up to this many files in memory. Large files
+    /// are still read from disk on every transfer
+    pub fn file_cache(mut self, capacity: Option<usize>) -> Self {
+        self.config.file_cache = capacity.filter(|&cap| cap > 0).map(FileCache::new);
+        self
+    }
+
+    /// When a file is not found, look for a unique match in its directory
+    /// ignoring case
+    pub fn case_insensitive(mut self, enabled: bool) -> Self {
+        self.config.case_insensitive = enabled;
+        self
+    }
+
+    /// Serve a plain text listing of the contents when a directory is
+    /// requested
+    pub fn enable_listing(mut self, enabled: bool) -> Self {
+        self.config.enable_listing = enabled;
+        self
+    }
+
+    /// Act as if files and directories whose name starts with a dot
+    /// didn't exist, wherever they are. On by default
+    pub fn hide_dotfiles(mut self, enabled: bool) -> Self {
+        self.config.hide_dotfiles = enabled;
+        self
+    }
+
+    /// When a file is missing, serve the decompressed contents of its
+    /// gzipped version (same name plus `.gz`) if there's one
+    pub fn auto_decompress(mut self, enabled: bool) -> Self {
+        self.config.auto_decompress = enabled;
+        self
+    }
+
+    /// Carry the transfers over the listening socket instead of a new
+    /// socket (and port) for each of them. Not what RFC 1350 describes,
+    /// but helps getting through NATs and firewalls that only let the
+    /// listening port through
+    pub fn single_port(mut self, enabled: bool) -> Self {
+        self.config.single_port = enabled;
+        self
+    }
+
+    /// Let other sockets bind the same port (SO_REUSEPORT), so that
+    /// several processes can share the load. Only available on Unix-like
+    /// systems that support the option; binding fails elsewhere
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.config.reuse_port = enabled;
+        self
+    }
+
+    /// Refuse requests with an option negotiation error when they carry
+    /// options but none of them make sense, instead of serving them
+    /// without options
+    pub fn strict_options(mut self, enabled: bool) -> Self {
+        self.config.strict_options = enabled;
+        self
+    }
+
+    /// Keep transfers going when the client's port changes midway, as
+    /// happens behind some NATs. Workers take packets from any port of
+    /// the client's address, and answer wherever the last one came from
+    pub fn roaming_clients(mut self, enabled: bool) -> Self {
+        self.config.roaming_clients = enabled;
+        self
+    }
+
+    /// How to keep requests from reaching outside of the root
+    pub fn traversal_policy(mut self, policy: TraversalPolicy) -> Self {
+        self.config.traversal = policy;
+        self
+    }
+
+    /// Error code for rejecting write requests, `DEFAULT_WRITE_REJECTION`
+    /// if not set. The message is always "No write permission"
+    pub fn write_rejection(mut self, code: ErrorCode) -> Self {
+        self.config.write_rejection = code;
+        self
+    }
+
+    /// If the client never acknowledges the options, carry on with a
+    /// plain transfer instead of aborting
+    pub fn oack_fallback(mut self, enabled: bool) -> Self {
+        self.config.transfer.oack_fallback = enabled;
+        self
+    }
+
+    /// Ignore any option requested by the clients, always doing plain
+    /// RFC 1350 transfers
+    pub fn no_options(mut self, disabled: bool) -> Self {
+        self.config.transfer.no_options = disabled;
+        self
+    }
+
+    /// Address for the HTTP endpoint exporting the server metrics
+    pub fn metrics_address(mut self, address: Option<SocketAddr>) -> Self {
+        self.config.metrics_address = address;
+        self
+    }
+
+    /// Append a dump of every packet sent or received to this file
+    pub fn trace_file(mut self, path: Option<PathBuf>) -> Self {
+        self.config.trace_file = path;
+        self
+    }
+
+    /// Confine the process to the root directory once the server is
+    /// bound. Needs the privileges to do so, which are not dropped
+    /// afterwards
+    #[cfg(unix)]
+    pub fn chroot(mut self, enabled: bool) -> Self {
+        self.config.chroot = enabled;
+        self
+    }
+
+    /// Hook deciding whether a client can read a file, called before
+    /// looking for it. Denied requests get an access violation error.
+    /// Other requests are served while it runs, so it may take its time
+    pub fn authorize<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(SocketAddr, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.config.authorizer = Authorizer(Some(Arc::new(move |peer, filename| Box::pin(hook(peer, filename)))));
+        self
+    }
+
+    /// Hook mapping a request to a file specific to the client, like the
+    /// per host configurations used for PXE booting. When it returns a
+    /// name, that file is served instead, unless it doesn't exist, in
+    /// which case the requested one is
+    pub fn resolve<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(SocketAddr, &str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.config.resolver = Resolver(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Hook called whenever a transfer ends, successfully or not, with a
+    /// summary of it. Meant for auditing
+    pub fn on_completion<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(TransferStats) + Send + Sync + 'static,
+    {
+        self.config.transfer.completion = Completion(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Channel receiving the progress of every transfer, at most once per
+    /// `interval` and when it ends. A zero interval reports every block.
+    /// Events that don't fit in the channel are dropped
+    pub fn progress(mut self, events: mpsc::Sender<TransferProgress>, interval: Duration) -> Self {
+        self.config.transfer.progress = Some(Progress { events, interval });
+        self
+    }
+
+    /// Cancel transfers still running after this long, no matter if the
+    /// client is still answering
+    pub fn max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.config.max_lifetime = lifetime;
+        self
+    }
+
+    /// Limit for new transfers per second, across all clients. Requests
+    /// over it are turned down as if the server was busy
+    pub fn global_rate(mut self, rate: Option<u32>) -> Self {
+        self.config.global_rate = rate;
+        self
+    }
+
+    /// Makes the transfers take turns to send their packets, with up to
+    /// `concurrent` of them sending at once, so that small transfers are
+    /// not held back by large ones under load
+    pub fn fair_scheduling(mut self, concurrent: Option<usize>) -> Self {
+        self.config.transfer.sends = concurrent.map(|concurrent| Arc::new(SendQueue::new(concurrent)));
+        self
+    }
+
+    pub fn build(self) -> Server {
+        Server { config: self.config }
+    }
+}
+
+#[derive(Debug)]
+pub struct Server {
+    config: Config,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config {
+                address: DEFAULT_ADDRESS,
+                port: DEFAULT_PORT,
+                static_root: PathBuf::from(DEFAULT_STATIC_ROOT),
+                virtual_files: MemoryProvider::new(),
+                path_mtu: None,
+                max_file_size: None,
+                max_tsize: None,
+                max_timeout: None,
+                file_cache: None,
+                metrics_address: None,
+                trace_file: None,
+                #[cfg(unix)]
+                chroot: false,
+                case_insensitive: false,
+                enable_listing: false,
+                hide_dotfiles: true,
+                strict_options: false,
+                auto_decompress: false,
+                single_port: false,
+                reuse_port: false,
+                roaming_clients: false,
+                traversal: TraversalPolicy::default(),
+                write_rejection: DEFAULT_WRITE_REJECTION,
+                authorizer: Authorizer::default(),
+                resolver: Resolver::default(),
+                max_lifetime: None,
+                global_rate: None,
+                workers: Arc::default(),
+                draining: Arc::default(),
+                transfer: TransferSettings::default(),
+            }
+        }
+    }
+
+    /// Validates the configuration without serving anything: the root
+    /// must be an existing directory (unless there are virtual files to
+    /// serve) and the listening port has to be available
+    pub async fn check(&self) -> Result<()> {
+        if self.config.root_required() {
+            validate_root(&self.config.static_root).await?;
+        }
+
+        if let Err(error) = bind_listening(&self.config) {
+            bail!("Cannot bind to port {}: {error}", self.config.port);
+        }
+
+        Ok(())
+    }
+
+    /// Binds the listening socket. Requests are not processed until the
+    /// returned listener is run, but they will be queued from this point.
+    /// Fails if the root is not a directory, unless there are virtual
+    /// files to serve
+    pub async fn bind(self) -> std::io::Result<Listener> {
+        let sock = bind_listening(&self.config)?;
+        Listener::from_socket(sock, self).await
+    }
+
+    /// Binds the listening socket and serves requests. Only returns on error
+    pub async fn run(self) -> std::io::Result<()> {
+        self.bind().await?.run().await
+    }
+
+    /// Like `run`, but also returns once `shutdown` completes
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> std::io::Result<()> {
+        self.bind().await?.run_until(shutdown).await
+    }
+}
+
+// Confines the process to the root, which becomes "/" from then on
+#[cfg(unix)]
+fn jail(config: &mut Config, chroot: impl FnOnce(&Path) -> std::io::Result<()>) -> std::io::Result<()> {
+    chroot(&config.static_root)?;
+    config.static_root = PathBuf::from("/");
+
+    Ok(())
+}
+
+fn bind_listening(config: &Config) -> std::io::Result<UdpSocket> {
+    let address = SocketAddr::new(config.address, config.port);
+    let sock = socket2::Socket::new(socket2::Domain::for_address(address), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if config.reuse_port {
+        set_reuse_port(&sock)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&address.into())?;
+
+    UdpSocket::from_std(sock.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(sock: &socket2::Socket) -> std::io::Result<()> {
+    sock.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_: &socket2::Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "SO_REUSEPORT is not supported on this platform"))
+}
+
+async fn validate_root(root: &Path) -> std::io::Result<()> {
+    match tokio::fs::metadata(root).await {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Root {} is not a directory", root.display()),
+        )),
+        Err(error) => Err(std::io::Error::new(
+            error.kind(),
+            format!("Cannot access root {}: {error}", root.display()),
+        )),
+    }
+}
+
+/// A server which is already bound to its listening socket
+#[derive(Debug)]
+pub struct Listener {
+    config: Config,
+    sock: UdpSocket,
+    metrics_listener: Option<TcpListener>,
+}
+
+impl Listener {
+    /// Serves requests arriving at a socket set up by the caller, like
+    /// one with socket options the server doesn't know about. The address
+    /// and port in the configuration are ignored, otherwise it's like
+    /// `Server::bind`
+    pub async fn from_socket(sock: UdpSocket, server: Server) -> std::io::Result<Listener> {
+        let mut config = server.config;
+        if config.root_required() {
+            validate_root(&config.static_root).await?;
+        }
+        // Resolved once and for all, so that a relative root doesn't
+        // depend on the working directory, and paths are checked against
+        // its actual location
+        match tokio::fs::canonicalize(&config.static_root).await {
+            Ok(root) => config.static_root = root,
+            // Not needed when there are only virtual files to serve
+            Err(_) if !config.root_required() => {}
+            Err(error) => return Err(std::io::Error::new(
+                error.kind(),
+                format!("Cannot resolve root {}: {error}", config.static_root.display()),
+            )),
+        }
+        if let Some(path) = &config.trace_file {
+            config.transfer.trace = Some(Arc::new(Trace::create(path)?));
+        }
+        let metrics_listener = match config.metrics_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        // Anything outside of the root has to be opened by now. If the
+        // privileges were to be dropped, it should happen after this
+        #[cfg(unix)]
+        if config.chroot {
+            jail(&mut config, |root| {
+                std::os::unix::fs::chroot(root)?;
+                std::env::set_current_dir("/")
+            })?;
+        }
+
+        Ok(Listener {
+            config,
+            sock,
+            metrics_listener,
+        })
+    }
+
+    /// Address the server is listening on. Useful to find out the actual
+    /// port when binding to port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// Counters updated as the server handles transfers
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.config.transfer.metrics.clone()
+    }
+
+    /// Transfers in progress, which can be counted or waited for
+    pub fn workers(&self) -> Arc<WorkerRegistry> {
+        self.config.workers.clone()
+    }
+
+    /// Switch for drain mode: while set, new requests are turned down as
+    /// if the server was busy, but the transfers in progress carry on
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        self.config.draining.clone()
+    }
+
+    /// Listens for requests, spawning a new task for each accepted
+    /// transfer. Only returns on error
+    pub async fn run(self) -> std::io::Result<()> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Like `run`, but stops accepting requests once `shutdown` completes.
+    /// Transfers already in progress are not affected
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> std::io::Result<()> {
+        tokio::pin!(shutdown);
+        let Listener { config, sock, metrics_listener } = self;
+        let config = Arc::new(config);
+        let sock = Arc::new(sock);
+        let settings = &config.transfer;
+        let local_addr = sock.local_addr()?;
+
+        if let Some(listener) = metrics_listener {
+            let metrics = settings.metrics.clone();
+            tokio::spawn(async move {
+                if let Err(error) = metrics.serve(listener).await {
+                    log::error!("Metrics endpoint stopped: {error}");
+                }
+            });
+        }
+
+        if let Some(max_lifetime) = config.max_lifetime {
+            tokio::spawn(config.workers.clone().run_reaper(max_lifetime));
+        }
+
+        let mut global_rate = config.global_rate.map(TokenBucket::new);
+
+        // Transfers going through the listening socket, in single port
+        // mode, by client address
+        let mut transfers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        // Clients with a transfer of their own in progress, along with
+        // what they asked for
+        let mut in_flight: HashMap<SocketAddr, InFlight> = HashMap::new();
+
+        // One extra byte, to tell apart requests that fill the buffer
+        // from the ones that didn't fit in it
+        let mut buf = [0; MAX_REQUEST_SIZE + 1];
+        loop {
+            let received = tokio::select! {
+                received = sock.recv_from(&mut buf) => received,
+                _ = &mut shutdown => return Ok(()),
+            };
+            let (len, addr) = match received {
+                Ok(received) => received,
+                Err(error) if is_transient(&error) => {
+                    log::warn!("While receiving a request: {error}");
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            settings.trace(Direction::Received, &sock, Some(addr), &buf[..len]);
+
+            if let Some(transfer) = transfers.get(&addr) {
+                if !transfer.is_closed() {
+                    // Dropped if the worker falls behind, as any other
+                    // lost packet
+                    let _ = transfer.try_send(buf[..len].to_vec());
+                    continue;
+                }
+                transfers.remove(&addr);
+            }
+
+            let is_request = matches!(
+                PacketType::from_packet(&buf[..len]),
+                Ok(PacketType::ReadRequest | PacketType::WriteRequest)
+            );
+            if is_request && len > MAX_REQUEST_SIZE {
+                send_error(
+                    &sock,
+                    ErrorCode::IllegalOperation.into_explicit_message("Request too large"),
+                    Dest::Addr(addr),
+                    settings,
+                    ).await;
+                continue;
+            }
+
+            let flags = ParserFlags { strict_options: config.strict_options, ..Default::default() };
+            match parse_message_with(&buf[..len], flags) {
+                Ok(message) => {
+                    match message {
+                        Message::Write { .. } => {
+                            send_error(
+                                &sock,
+                                config.write_rejection.into_explicit_message("No write permission"),
+                                Dest::Addr(addr),
+                                settings,
+                                ).await
+                        }
+                        // A retransmission of a request already being
+                        // served, as the client didn't hear back in time.
+                        // Handed to the worker, which may need to fall
+                        // back to a transfer without options
+                        Message::Read { ref filename, ref mode, .. } if in_flight.get(&addr).is_some_and(|transfer| transfer.repeats(filename, mode)) => {
+                            log::debug!("{addr}: repeated request, already being served");
+                            // Dropped if the worker falls behind, as any
+                            // other lost packet
+                            let _ = in_flight[&addr].worker.try_send(buf[..len].to_vec());
+                        }
+                        Message::Read { filename, mode, options, ignored } => {
+                            if !ignored.is_empty() {
+                                log::info!("{addr}: ignoring unsupported options: {}", ignored.join(", "));
+                            }
+                            // Checked first, as it's the cheapest way of
+                            // shedding load
+                            if config.draining.load(Ordering::Relaxed)
+                                || global_rate.as_mut().is_some_and(|bucket| !bucket.try_acquire()) {
+                                send_error(
+                                    &sock,
+                                    ErrorCode::NotDefined.into_explicit_message("Server busy"),
+                                    Dest::Addr(addr),
+                                    settings,
+                                    ).await
+                            } else if mode != Mode::Octet {
+                                send_error(
+                                    &sock, 
+                                    ErrorCode::IllegalOperation
+                                        .into_explicit_message("Only Octet transfers are supported"),
+                                    Dest::Addr(addr),
+                                    settings,
+                                    ).await
+                            } else {
+                                // Set up right away, so that whatever comes
+                                // from the client while the transfer is being
+                                // prepared reaches it
+                                let route = if config.single_port {
+                                    let (sender, incoming) = mpsc::channel(TRANSFER_QUEUE);
+                                    transfers.retain(|_, transfer| !transfer.is_closed());
+                                    transfers.insert(addr, sender);
+                                    Route::Shared(Channel::Shared { sock: sock.clone(), peer: addr, incoming })
+                                } else {
+                                    let (sender, repeats) = mpsc::channel(TRANSFER_QUEUE);
+                                    in_flight.retain(|_, transfer| !transfer.worker.is_closed());
+                                    in_flight.insert(addr, InFlight { filename: filename.clone(), mode, worker: sender });
+                                    Route::Own { local: local_addr, repeats }
+                                };
+                                tokio::spawn(start_transfer(config.clone(), sock.clone(), addr, filename, options, route));
+                            }
+                        }
+                        // Most likely leftovers from finished transfers. RFC 1350
+                        // forbids answering to errors, and ACKs are harmless
+                        Message::Ack(..) | Message::Error { .. } => {}
+                        Message::Data { .. } => {
+                            send_error(
+                                &sock,
+                                ErrorCode::UnknownTransferId.into_message(),
+                                Dest::Addr(addr),
+                                settings,
+                                ).await
+                        }
+                        _ => {
+                            send_error(
+                                &sock,
+                                ErrorCode::IllegalOperation.into_message(),
+                                Dest::Addr(addr),
+                                settings,
+                                ).await
+                        }
+                    }
+                },
+                // Only strict mode bothers telling the client
+                Err(ParseError::InvalidOption(error)) if is_request && config.strict_options => {
+                    send_error(
+                        &sock,
+                        ErrorCode::OptionNegotiationError.into_explicit_message(&format!("Invalid options: {error}")),
+                        Dest::Addr(addr),
+                        settings,
+                        ).await;
+                }
+                Err(error) => {
+                    log::info!("While parsing message: {error}");
+                },
+            }
+        }
+    }
+}
+
+// Where a transfer is carried over, while it's being prepared
+enum Route {
+    // The listening socket (single port mode)
+    Shared(Channel),
+    // A socket of its own, bound once the request is accepted. Repeated
+    // requests arrive through the listener anyway
+    Own {
+        local: SocketAddr,
+        repeats: mpsc::Receiver<Vec<u8>>,
+    },
+}
+
+// Everything that may take a while before the first packet goes out
+// (asking the authorization hook, opening the file, measuring it for
+// tsize, binding a socket) happens here, so that the listener can go on
+// with other requests
+async fn start_transfer(config: Arc<Config>, sock: Arc<UdpSocket>, addr: SocketAddr, filename: String, options: Vec<TftpOption>, route: Route) {
+    let settings = &config.transfer;
+    let accepted = async {
+        if !config.authorizer.allows(addr, &filename).await {
+            return Err(ErrorCode::AccessViolation.into_message());
+        }
+        let mut source = open_for_client(&config, addr, &filename).await?;
+        let size = transfer_size(&mut source, &options).await?;
+        let granted = negotiate_options(&config, options.clone(), size)?;
+        log_negotiation(addr, &options, &granted);
+        Ok((source, granted))
+    }.await;
+    let (source, options) = match accepted {
+        Ok(accepted) => accepted,
+        Err(errmsg) => {
+            send_error(&sock, errmsg, Dest::Addr(addr), settings).await;
+            return;
+        }
+    };
+
+    let channel = match route {
+        Route::Shared(channel) => channel,
+        Route::Own { local, repeats } => match worker_channel(local, addr, config.roaming_clients, repeats).await {
+            Ok(channel) => channel,
+            // Out of ports or file descriptors, most likely. Other
+            // clients may still be served
+            Err(error) => {
+                log::warn!("{addr}: cannot set up a socket for the transfer: {error}");
+                send_error(
+                    &sock,
+                    ErrorCode::NotDefined.into_explicit_message("Cannot start transfer"),
+                    Dest::Addr(addr),
+                    settings,
+                    ).await;
+                return;
+            }
+        },
+    };
+
+    let handle = config.workers.register();
+    let worker = worker_task(channel, filename, source, options, settings.clone());
+    if !handle.supervise(worker).await {
+        log::warn!("{addr}: transfer cancelled, over the maximum lifetime");
+    }
+}
+
+// Errors on the listening socket that don't prevent serving further
+// requests. Some systems report ICMP errors caused by a previous reply
+// to a client, which are not a problem of the socket itself
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+// Address for the socket of a new transfer: same IP as the listening
+// socket, and any free port. Link-local IPv6 peers are only reachable
+// through the interface they came from, so their scope must be kept
+fn worker_address(local: SocketAddr, peer: SocketAddr) -> SocketAddr {
+    match (local, peer) {
+        (SocketAddr::V6(local), SocketAddr::V6(peer)) => {
+            let scope_id = if local.scope_id() != 0 { local.scope_id() } else { peer.scope_id() };
+            SocketAddrV6::new(*local.ip(), 0, 0, scope_id).into()
+        }
+        (local, _) => SocketAddr::new(local.ip(), 0),
+    }
+}
+
+// Sets up the socket a new transfer is carried over, on a port of its own
+async fn worker_channel(local: SocketAddr, peer: SocketAddr, roaming: bool, repeats: mpsc::Receiver<Vec<u8>>) -> std::io::Result<Channel> {
+    let sock = UdpSocket::bind(worker_address(local, peer)).await?;
+    if roaming {
+        return Ok(Channel::Roaming { sock, peer, repeats });
+    }
+    sock.connect(peer).await?;
+
+    Ok(Channel::Connected { sock, repeats })
+}
+
+async fn open_file(config: &Config, filename: &str) -> Result<Box<dyn TransferSource>, Message> {
+    // Not found rather than denied, so that their existence isn't leaked
+    if config.hide_dotfiles && is_hidden(filename) {
+        return Err(ErrorCode::FileNotFound.into_message());
+    }
+    let source: Box<dyn TransferSource> = match config.virtual_files.open(filename) {
+        Some(source) => Box::new(source),
+        None => open_from_root(config, filename).await?,
+    };
+
+    // Only sources with a known size can be checked in advance
+    if let (Some(max_size), Some(size)) = (config.max_file_size, source.size()) {
+        if size > max_size {
+            return Err(ErrorCode::AccessViolation.into_explicit_message("File too large to serve"));
+        }
+    }
+
+    Ok(source)
+}
+
+// Whether any part of the path is a dotfile. The "." and ".." components
+// are left to the traversal checks
+fn is_hidden(filename: &str) -> bool {
+    Path::new(filename).components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+// Like `open_file`, trying first the client specific file, if any
+async fn open_for_client(config: &Config, peer: SocketAddr, filename: &str) -> Result<Box<dyn TransferSource>, Message> {
+    if let Some(resolved) = config.resolver.resolve(peer, filename) {
+        match open_file(config, &resolved).await {
+            Err(Message::Error { code: ErrorCode::FileNotFound, .. }) => {}
+            opened => return opened,
+        }
+    }
+
+    open_file(config, filename).await
+}
+
+/// How `resolve_path` makes sure that requests stay within the root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraversalPolicy {
+    /// Looks at the filename alone, rejecting any parent directory
+    /// component, even if the result would still be inside the root
+    #[default]
+    Lexical,
+    /// Resolves the actual location of the file, following `..` and
+    /// symbolic links, and checks that it's under the root. Files that
+    /// don't exist get the lexical check instead
+    Canonical,
+    /// No checks at all. Only for servers confined to the root through
+    /// chroot, where nothing outside of it can be reached anyway
+    TrustChroot,
+}
+
+/// Translates a filename requested by a client into a path under `root`.
+/// Filenames are always relative to the root, even if they start with a
+/// slash, and those trying to get out of it are an access violation
+pub fn resolve_path(root: &Path, filename: &str, policy: TraversalPolicy) -> Result<PathBuf, Message> {
+    let relative = Path::new(filename.trim_start_matches('/'));
+    let path = root.join(relative);
+    let illegal = || ErrorCode::AccessViolation.into_explicit_message("Illegal path");
+
+    match policy {
+        TraversalPolicy::TrustChroot => return Ok(path),
+        TraversalPolicy::Canonical => {
+            if let (Ok(canonical_root), Ok(canonical)) = (root.canonicalize(), path.canonicalize()) {
+                return match canonical.starts_with(canonical_root) {
+                    true => Ok(path),
+                    false => Err(illegal()),
+                };
+            }
+        }
+        TraversalPolicy::Lexical => {}
+    }
+
+    // Verify that appending the filename hasn't directed out of the
+    // filesystem root. `starts_with` alone doesn't catch parent
+    // directory components, which are rejected anywhere in the path
+    let escapes = relative.components().any(|component| component == Component::ParentDir);
+    if escapes || !path.starts_with(root) {
+        return Err(illegal());
+    }
+
+    Ok(path)
+}
+
+async fn open_from_root(config: &Config, filename: &str) -> Result<Box<dyn TransferSource>, Message> {
+    let path = resolve_path(&config.static_root, filename, config.traversal)?;
+
+    if tokio::fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_dir()) {
+        if !config.enable_listing {
+            return Err(ErrorCode::AccessViolation.into_explicit_message("Is a directory"));
+        }
+        return match list_directory(&path, config.hide_dotfiles).await {
+            Ok(listing) => Ok(Box::new(Cursor::new(listing))),
+            Err(error) => Err(io_error_message(&error)),
+        };
+    }
+
+    let opened = match open_path(config, &path).await {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound && config.case_insensitive => {
+            match find_ignoring_case(&path).await {
+                Some(found) => open_path(config, &found).await,
+                None => Err(error),
+            }
+        }
+        opened => opened,
+    };
+    let opened = match opened {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound && config.auto_decompress => {
+            let mut compressed = path.into_os_string();
+            compressed.push(".gz");
+            match OpenOptions::new().read(true).open(&compressed).await {
+                Ok(file) => Ok(Box::new(GzipSource::new(file)) as Box<dyn TransferSource>),
+                Err(_) => Err(error),
+            }
+        }
+        opened => opened,
+    };
+
+    opened.map_err(|error| io_error_message(&error))
+}
+
+async fn open_path(config: &Config, path: &Path) -> std::io::Result<Box<dyn TransferSource>> {
+    if let Some(cache) = &config.file_cache {
+        if let Some(source) = cache.open(path).await? {
+            return Ok(Box::new(source));
+        }
+    }
+
+    let file = OpenOptions::new().read(true).open(path).await?;
+
+    Ok(Box::new(FileSource::new(file).await?))
+}
+
+// One entry per line, sorted by name. Directories are marked with a
+// trailing slash
+async fn list_directory(path: &Path, hide_dotfiles: bool) -> std::io::Result<Vec<u8>> {
+    let mut names = vec![];
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if hide_dotfiles && name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let listing = names.iter().map(|name| format!("{name}\n")).collect::<String>();
+
+    Ok(listing.into_bytes())
+}
+
+// Looks for an entry in the same directory whose name only differs in
+// case. Ambiguous names (more than one match) are not resolved
+async fn find_ignoring_case(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let mut entries = tokio::fs::read_dir(path.parent()?).await.ok()?;
+
+    let mut found = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let matches = entry.file_name().to_str()
+            .is_some_and(|candidate| candidate.to_lowercase() == name.to_lowercase());
+        if matches {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(entry.path());
+        }
+    }
+
+    found
+}
+
+// Translates I/O errors, either reading or writing files, into the
+// closest TFTP error
+fn io_error_message(error: &std::io::Error) -> Message {
+    match error.kind() {
+        std::io::ErrorKind::NotFound => {
+            ErrorCode::FileNotFound.into_message()
+        }
+        std::io::ErrorKind::PermissionDenied => {
+            ErrorCode::AccessViolation.into_explicit_message("Permission denied")
+        }
+        std::io::ErrorKind::AlreadyExists => {
+            ErrorCode::FileAlreadyExists.into_message()
+        }
+        std::io::ErrorKind::StorageFull => {
+            ErrorCode::DiskFull.into_message()
+        }
+        _ => ErrorCode::NotDefined.into_explicit_message(&format!("{error}")),
+    }
+}
+
+async fn read_block(source: &mut Box<dyn TransferSource>, block_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0; block_size];
+    let mut len = 0;
+    // Only a short block marks the end of the transfer, but pipes and
+    // devices may return less data than requested before reaching their
+    // end, so keep reading until the block is full
+    while len < block_size {
+        match source.read(&mut buffer[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    buffer.truncate(len);
+
+    Ok(buffer)
+}
+
+// Where a packet goes: the peer of a connected socket, or an explicit
+// address
+enum Dest {
+    Fixed,
+    Addr(SocketAddr),
+}
+
+async fn send_packet(sock: &UdpSocket, packet: &[u8], to: Dest, settings: &TransferSettings) -> std::io::Result<usize> {
+    match to {
+        Dest::Fixed => {
+            settings.trace(Direction::Sent, sock, None, packet);
+            sock.send(packet).await
+        }
+        Dest::Addr(addr) => {
+            settings.trace(Direction::Sent, sock, Some(addr), packet);
+            sock.send_to(packet, addr).await
+        }
+    }
+}
+
+// Encodes an error, accounting for it in the metrics
+fn error_packet(msg: Message, settings: &TransferSettings) -> Vec<u8> {
+    if let Message::Error { code, .. } = msg {
+        settings.metrics.error_sent(code);
+    }
+
+    msg.into_packet()
+}
+
+async fn send_error(sock: &UdpSocket, msg: Message, to: Dest, settings: &TransferSettings) {
+    let packet = error_packet(msg, settings);
+    let res = send_packet(sock, &packet, to, settings).await;
+
+    if let Err(error) = res {
+        log::warn!("While trying to send an error message: {error:?}");
+    }
+}
+
+// Size reported for the tsize option. Sources that don't know it in
+// advance (like compressed files) are measured, but only if the client
+// asked for it, as it may take a while
+async fn transfer_size(source: &mut Box<dyn TransferSource>, options: &[TftpOption]) -> Result<Option<u64>, Message> {
+    let requested = options.iter().any(|opt| matches!(opt, TftpOption::TransferSize(..)));
+    if !requested || source.size().is_some() {
+        return Ok(source.size());
+    }
+
+    source.measure().await.map_err(|error| io_error_message(&error))
+}
+
+// Adjusts the options requested by the client to the values the server
+// will actually use, dropping the ones it can't honor. The result is what
+// gets acknowledged to the client (RFC 2347), and keeps the order of the
+// request, as some strict clients expect
+fn negotiate_options(config: &Config, options: Vec<TftpOption>, size: Option<u64>) -> Result<Vec<TftpOption>, Message> {
+    let mut negotiated = vec![];
+    if config.transfer.no_options {
+        return Ok(negotiated);
+    }
+
+    for opt in options {
+        match opt {
+            TftpOption::BlockSize(bls) => {
+                let max_size = config.path_mtu.map_or(bls, |mtu| mtu - DATA_OVERHEAD);
+                negotiated.push(TftpOption::BlockSize(bls.min(max_size)));
+            }
+            // The size is reported back only if it's known in advance
+            TftpOption::TransferSize(tsize) => match size {
+                _ if config.max_tsize.is_some_and(|max| tsize > max) => {
+                    return Err(ErrorCode::OptionNegotiationError.into_explicit_message("Declared size too large"));
+                }
+                Some(fsize) if tsize > fsize => {
+                    return Err(ErrorCode::OptionNegotiationError.into_explicit_message("File too large"));
+                }
+                Some(fsize) => negotiated.push(TftpOption::TransferSize(fsize)),
+                None => {}
+            },
+            TftpOption::Timeout(tout) => {
+                let max = config.max_timeout.unwrap_or(tout);
+                negotiated.push(TftpOption::Timeout(tout.min(max)));
+            }
+            TftpOption::MicroTimeout(tout) => {
+                let max = config.max_timeout.map_or(tout, |max| max as u32 * 1_000_000);
+                negotiated.push(TftpOption::MicroTimeout(tout.min(max)));
+            }
+            TftpOption::Unknown { .. } => {}
+            opt => negotiated.push(opt),
+        }
+    }
+    let negotiated = check_options(negotiated)
+        .map_err(|error| ErrorCode::OptionNegotiationError.into_explicit_message(&error.to_string()))?;
+
+    // Starting past the end of the file makes no sense
+    if let (Some(start), Some(fsize)) = (get_start_block(&negotiated), size) {
+        let offset = (start as u64 - 1) * get_block_size(&negotiated) as u64;
+        if offset > fsize {
+            return Err(ErrorCode::OptionNegotiationError.into_explicit_message("Start block past the end of the file"));
+        }
+    }
+
+    Ok(negotiated)
+}
+
+// Shows what became of each option the client asked for, which helps
+// when a client doesn't like what it gets
+fn log_negotiation(peer: SocketAddr, requested: &[TftpOption], granted: &[TftpOption]) {
+    for option in requested {
+        match granted.iter().find(|granted| granted.name() == option.name()) {
+            Some(granted) => log::debug!("{peer}: requested {option}, granted {granted}"),
+            None => log::debug!("{peer}: requested {option}, not granted"),
+        }
+    }
+}
+
+fn get_block_size(options: &[TftpOption]) -> usize {
+    for opt in options {
+        if let TftpOption::BlockSize(bls) = opt {
+            return *bls as usize;
+        }
+    }
+
+    BLOCK_SIZE
+}
+
+// Timeout used both for the option negotiation and the data phase. The
+// option is expressed in seconds (RFC 2349), unless the client asked for
+// microseconds, which take precedence
+fn get_timeout(options: &[TftpOption]) -> Duration {
+    for opt in options {
+        if let TftpOption::MicroTimeout(tout) = opt {
+            return Duration::from_micros(*tout as u64);
+        }
+    }
+    for opt in options {
+        if let TftpOption::Timeout(tout) = opt {
+            return Duration::from_secs(*tout as u64);
+        }
+    }
+
+    DEFAULT_TIMEOUT
+}
+
+// Time allowed for a DATA block to be acknowledged: the negotiated timeout
+// covers the round trip, plus whatever it takes to transmit the block on
+// a slow link, which only becomes noticeable with large blocks
+fn effective_timeout(timeout: Duration, block_size: usize) -> Duration {
+    timeout + Duration::from_secs_f64(block_size as f64 / ASSUMED_LINK_RATE)
+}
+
+// Block number the counter wraps to after 65535, unless the client asked
+// for something else
+fn get_rollover(options: &[TftpOption]) -> u16 {
+    for opt in options {
+        if let TftpOption::Rollover(rollover) = opt {
+            return *rollover as u16;
+        }
+    }
+
+    0
+}
+
+fn get_start_block(options: &[TftpOption]) -> Option<u16> {
+    for opt in options {
+        if let TftpOption::StartBlock(block) = opt {
+            return Some(*block);
+        }
+    }
+
+    None
+}
+
+fn next_block(block: u16, rollover: u16) -> u16 {
+    if block == u16::MAX {
+        rollover
+    } else {
+        block + 1
+    }
+}
+
+// How the client answered to a packet
+#[derive(Debug, PartialEq)]
+enum Response {
+    Ack,
+    // The client sent its request again instead of acknowledging the
+    // OACK, which means that it doesn't understand options
+    RepeatedRequest,
+}
+
+// Error sent by the client in the middle of a transfer
+#[derive(Debug)]
+struct ClientAbort {
+    code: ErrorCode,
+    message: String,
+}
+
+impl std::fmt::Display for ClientAbort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfer aborted by the client ({:?}): {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ClientAbort {}
+
+// The client never answered, however many times the packet was sent
+#[derive(Debug)]
+struct NoResponse;
+
+impl std::fmt::Display for NoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many retries")
+    }
+}
+
+impl std::error::Error for NoResponse {}
+
+// Something from the client that ended the transfer, after sending it
+// the error packet saying why
+#[derive(Debug)]
+struct Rejected(&'static str);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rejected packet from the client: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// Errors reported when the client is gone (e.g. after an ICMP port
+// unreachable), as opposed to transient ones
+fn is_unreachable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+// Like `recv`, but also wakes up on errors queued on the socket (e.g. an
+// ICMP port unreachable), which a plain `recv` would only notice when
+// something else arrives
+async fn recv_or_error(sock: &UdpSocket, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let ready = sock.ready(Interest::READABLE | Interest::ERROR).await?;
+        if ready.is_error() {
+            // The error readiness is only consumed by an operation that
+            // would block. Clear it before picking up the actual error
+            let _ = sock.try_io(Interest::ERROR, || Err::<(), _>(std::io::ErrorKind::WouldBlock.into()));
+            if let Some(error) = sock.take_error()? {
+                return Err(error);
+            }
+        }
+        if ready.is_readable() {
+            match sock.try_recv(buf) {
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+// The worker end of a transfer: either a socket of its own, connected to
+// the client, or the listening socket (in single port mode), in which
+// case the listener forwards the packets coming from the client. With
+// roaming clients, the socket is not connected, so that the client can
+// switch ports. Workers with a socket of their own still get repeated
+// requests through the listener
+enum Channel {
+    Connected {
+        sock: UdpSocket,
+        repeats: mpsc::Receiver<Vec<u8>>,
+    },
+    Shared {
+        sock: Arc<UdpSocket>,
+        peer: SocketAddr,
+        incoming: mpsc::Receiver<Vec<u8>>,
+    },
+    Roaming {
+        sock: UdpSocket,
+        peer: SocketAddr,
+        repeats: mpsc::Receiver<Vec<u8>>,
+    },
+}
+
+// A client request being served by a worker with its own socket
+struct InFlight {
+    filename: String,
+    mode: Mode,
+    worker: mpsc::Sender<Vec<u8>>,
+}
+
+impl InFlight {
+    // Only the very same request counts as a retransmission. Anything else
+    // is a new transfer
+    fn repeats(&self, filename: &str, mode: &Mode) -> bool {
+        !self.worker.is_closed() && self.filename == filename && self.mode == *mode
+    }
+}
+
+impl Channel {
+    fn target(&self) -> (&UdpSocket, Dest) {
+        match self {
+            Channel::Connected { sock, .. } => (sock, Dest::Fixed),
+            Channel::Shared { sock, peer, .. } => (sock, Dest::Addr(*peer)),
+            Channel::Roaming { sock, peer, .. } => (sock, Dest::Addr(*peer)),
+        }
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Channel::Connected { sock, .. } => sock.peer_addr(),
+            Channel::Shared { peer, .. } | Channel::Roaming { peer, .. } => Ok(*peer),
+        }
+    }
+
+    async fn send(&self, packet: &[u8], settings: &TransferSettings) -> std::io::Result<usize> {
+        let (sock, to) = self.target();
+        // Only for as long as the packet is being sent. Waiting for the
+        // client to answer doesn't hold back anyone else
+        let _turn = match &settings.sends {
+            Some(queue) => Some(queue.turn().await),
+            None => None,
+        };
+        send_packet(sock, packet, to, settings).await
+    }
+
+    async fn send_error(&self, msg: Message, settings: &TransferSettings) {
+        let (sock, to) = self.target();
+        send_error(sock, msg, to, settings).await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8], settings: &TransferSettings) -> std::io::Result<usize> {
+        match self {
+            Channel::Connected { sock, repeats } => tokio::select! {
+                received = recv_or_error(sock, buf) => {
+                    let len = received?;
+                    settings.trace(Direction::Received, sock, None, &buf[..len]);
+                    Ok(len)
+                }
+                // Already traced by the listener
+                Some(packet) = repeats.recv() => Ok(copy_packet(&packet, buf)),
+            },
+            // Already traced by the listener
+            Channel::Shared { incoming, .. } => match incoming.recv().await {
+                Some(packet) => Ok(copy_packet(&packet, buf)),
+                // The listener is gone, so nothing else will arrive
+                None => std::future::pending().await,
+            },
+            // Anything from the client's address is taken, whatever the
+            // port, which becomes the one answered from then on
+            Channel::Roaming { sock, peer, repeats } => loop {
+                let (len, from) = tokio::select! {
+                    received = sock.recv_from(buf) => received?,
+                    Some(packet) = repeats.recv() => return Ok(copy_packet(&packet, buf)),
+                };
+                settings.trace(Direction::Received, sock, Some(from), &buf[..len]);
+                if from.ip() == peer.ip() {
+                    if from != *peer {
+                        log::info!("{peer}: client moved to {from}");
+                        *peer = from;
+                    }
+                    return Ok(len);
+                }
+                send_error(sock, ErrorCode::UnknownTransferId.into_message(), Dest::Addr(from), settings).await;
+            },
+        }
+    }
+}
+
+// Copies a packet forwarded by the listener, as if it had been received
+fn copy_packet(packet: &[u8], buf: &mut [u8]) -> usize {
+    let len = packet.len().min(buf.len());
+    buf[..len].copy_from_slice(&packet[..len]);
+    len
+}
+
+// Sends a packet until it's acknowledged, returning the client response
+// along with the number of retransmissions it took
+async fn packet_and_ack(channel: &mut Channel, block: u16, packet: &[u8], tout: Duration, settings: &TransferSettings) -> Result<(Response, usize)> {
+    // One extra byte, to tell apart the packets that didn't fit
+    let mut read_buffer = [0; MAX_CONTROL_SIZE + 1];
+    let mut failed_attempts = 0;
+    let mut waiting_for_ack = false;
+    let mut current_tout = tout;
+    // Packets that don't acknowledge anything don't extend the wait, or a
+    // steady stream of them would keep the transfer from ever timing out
+    let mut deadline = Instant::now();
+    while failed_attempts < MAX_ATTEMPTS {
+        if !waiting_for_ack {
+            if let Err(error) = channel.send(packet, settings).await {
+                if is_unreachable(&error) {
+                    return Err(error.into());
+                }
+                // Abort, something really wrong happened here
+                bail!("Critical error attemting to send packet");
+            }
+            waiting_for_ack = true;
+            deadline = Instant::now() + current_tout;
+        } else if let Ok(received) = timeout_at(deadline, channel.recv(&mut read_buffer, settings)).await {
+            let len = match received {
+                // No point in waiting for the client after this
+                Err(error) if is_unreachable(&error) => return Err(error.into()),
+                Err(_) => continue,
+                // An empty datagram carries nothing, and whatever is in
+                // the buffer is left over from a previous packet
+                Ok(0) => continue,
+                // The error ends the transfer for the client (RFC 1350)
+                Ok(len) if len > MAX_CONTROL_SIZE => {
+                    channel.send_error(ErrorCode::IllegalOperation.into_explicit_message("Packet too large"), settings).await;
+                    return Err(Rejected("Packet too large").into());
+                }
+                Ok(len) => len,
+            };
+            // A short ACK would otherwise be ignored, and a long one
+            // taken at face value
+            let is_ack = PacketType::from_packet(&read_buffer[..len]).is_ok_and(|kind| kind == PacketType::Acknowledgement);
+            if is_ack && len != ACK_SIZE {
+                channel.send_error(ErrorCode::IllegalOperation.into_explicit_message("Malformed ACK"), settings).await;
+                return Err(Rejected("Malformed ACK").into());
+            }
+            if let Ok(message) = parse_message(&read_buffer[..len]) {
+                match message {
+                    Message::Ack(block_id) => {
+                        // Transfers go in lockstep, so anything but the
+                        // last block sent is a duplicate or bogus
+                        if block_id == block {
+                            return Ok((Response::Ack, failed_attempts));
+                        }
+                    }
+                    Message::Read { .. } if block == 0 => {
+                        return Ok((Response::RepeatedRequest, failed_attempts));
+                    }
+                    // A late copy of the request, once the transfer is
+                    // already going
+                    Message::Read { .. } => {}
+                    // The client gave up, and must not be answered (RFC 1350)
+                    Message::Error { code, message } => {
+                        return Err(ClientAbort { code, message }.into());
+                    }
+                    _ => {
+                        channel.send_error(ErrorCode::IllegalOperation.into_message(), settings).await;
+                    }
+                };
+            }
+        } else {
+            failed_attempts += 1;
+            log::debug!("Timeout (failed: {failed_attempts}/{MAX_ATTEMPTS})");
+            waiting_for_ack = false;
+            current_tout = current_tout.saturating_mul(2).min(MAX_BACKOFF_TIMEOUT.max(tout));
+        }
+    }
+
+    Err(NoResponse.into())
+}
+
+// Lets the client know that the transfer is over, unless it's already gone
+// or was the one ending it
+async fn abort_transfer(channel: &Channel, error: anyhow::Error, settings: &TransferSettings) {
+    log::warn!("{error}");
+    let client_gone = error.downcast_ref::<std::io::Error>().is_some_and(is_unreachable)
+        || error.is::<ClientAbort>();
+    if !client_gone && !error.is::<Rejected>() {
+        channel.send_error(ErrorCode::NotDefined.into_explicit_message("Transfer aborted"), settings).await;
+    }
+}
+
+// Retransmissions needed during a transfer
+#[derive(Debug, Default)]
+struct RetryStats {
+    // Worst case for a single packet
+    max: usize,
+    total: usize,
+}
+
+impl RetryStats {
+    fn record(&mut self, retries: usize) {
+        self.max = self.max.max(retries);
+        self.total = self.total.saturating_add(retries);
+    }
+}
+
+// Values a transfer runs with, once the options are agreed on
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Parameters {
+    block_size: usize,
+    timeout: Duration,
+    rollover: u16,
+    start_block: u16,
+}
+
+impl Parameters {
+    // With no options, these are the ones of a plain RFC 1350 transfer
+    fn from_options(options: &[TftpOption]) -> Self {
+        Parameters {
+            block_size: get_block_size(options),
+            timeout: get_timeout(options),
+            rollover: get_rollover(options),
+            start_block: get_start_block(options).unwrap_or(1),
+        }
+    }
+}
+
+// The window size is always one block, as transfers go in lockstep (the
+// windowsize option of RFC 7440 is not supported)
+impl std::fmt::Display for Parameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "blksize={} timeout={:?} rollover={} startblock={} windowsize=1",
+            self.block_size, self.timeout, self.rollover, self.start_block
+        )
+    }
+}
+
+async fn worker_task(mut channel: Channel, filename: String, mut source: Box<dyn TransferSource>, options: Vec<TftpOption>, settings: TransferSettings) {
+    let metrics = &settings.metrics;
+    let _active = metrics.transfer_started();
+    let mut report = settings.completion.report(channel.peer_addr().ok(), &filename);
+    let options = if settings.no_options { vec![] } else { options };
+    let mut parameters = Parameters::from_options(&options);
+    let mut retries = RetryStats::default();
+
+    if !options.is_empty() {
+        let msg = Message::OptionAck { options }.into_packet();
+        match packet_and_ack(&mut channel, 0, &msg, parameters.timeout, &settings).await {
+            Ok((Response::RepeatedRequest, attempts)) => {
+                retries.record(attempts);
+                // Fall back to a plain RFC 1350 transfer
+                parameters = Parameters::from_options(&[]);
+            }
+            Ok((Response::Ack, attempts)) => retries.record(attempts),
+            // Only for clients that stay silent. One that answered with an
+            // error (or is gone) has ended the transfer
+            Err(error) if settings.oack_fallback && error.is::<NoResponse>() => {
+                log::info!("{error}, falling back to a plain transfer");
+                parameters = Parameters::from_options(&[]);
+            }
+            Err(error) => {
+                // Never carry on with options the client didn't agree to
+                abort_transfer(&channel, error, &settings).await;
+                return;
+            }
+        }
+    }
+
+    match channel.peer_addr() {
+        Ok(peer) => log::info!("{peer}: sending {filename} (octet), {parameters}"),
+        Err(_) => log::info!("Sending {filename} (octet), {parameters}"),
+    }
+    let Parameters { block_size, timeout, rollover, start_block } = parameters;
+    let tout = effective_timeout(timeout, block_size);
+
+    // The blocks before the start are never sent
+    let skipped = u64::from(start_block - 1) * block_size as u64;
+    if let Err(error) = source.skip(skipped).await {
+        channel.send_error(io_error_message(&error), &settings).await;
+        return;
+    }
+
+    let size = source.size();
+    let mut last_progress = Instant::now();
+    let mut current_block: u16 = start_block - 1;
+    loop {
+        current_block = next_block(current_block, rollover);
+        let payload = match read_block(&mut source, block_size).await {
+            Ok(data) => data,
+            Err(error) => {
+                channel.send_error(io_error_message(&error), &settings).await;
+                break;
+            }
+        };
+        let payload_len = payload.len();
+
+        let message = Message::Data { block: current_block, payload }.into_packet();
+
+        match packet_and_ack(&mut channel, current_block, &message, tout, &settings).await {
+            Ok((_, attempts)) => retries.record(attempts),
+            Err(error) => {
+                abort_transfer(&channel, error, &settings).await;
+                break;
+            }
+        }
+        metrics.bytes_sent(payload_len as u64);
+        report.stats.bytes += payload_len as u64;
+        if let Some(progress) = &settings.progress {
+            if payload_len < block_size || last_progress.elapsed() >= progress.interval {
+                let _ = progress.events.try_send(TransferProgress {
+                    client: report.stats.client,
+                    filename: filename.clone(),
+                    bytes: report.stats.bytes,
+                    size,
+                });
+                last_progress = Instant::now();
+            }
+        }
+
+        if payload_len < block_size {
+            report.stats.success = true;
+            break;
+        }
+    }
+
+    if retries.total > 0 {
+        log::info!("Transfer needed {} retransmissions (at most {} for a single packet)", retries.total, retries.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, Write};
+
+    async fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let worker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        worker.connect(client.local_addr().unwrap()).await.unwrap();
+        client.connect(worker.local_addr().unwrap()).await.unwrap();
+
+        (worker, client)
+    }
+
+    fn connected(sock: UdpSocket) -> Channel {
+        let (_, repeats) = mpsc::channel(1);
+        Channel::Connected { sock, repeats }
+    }
+
+    async fn file_with_contents(data: &[u8]) -> Box<dyn TransferSource> {
+        let mut tmp = tempfile::tempfile().unwrap();
+        tmp.write_all(data).unwrap();
+        tmp.rewind().unwrap();
+        Box::new(FileSource::new(tokio::fs::File::from_std(tmp)).await.unwrap())
+    }
+
+    // Behaves like special files do: reports no size, and hands over the
+    // contents in small chunks
+    struct UnsizedSource(std::io::Cursor<Vec<u8>>);
+
+    impl tokio::io::AsyncRead for UnsizedSource {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let mut chunk = [0; 100];
+            let len = chunk.len().min(buf.remaining());
+            let mut chunk_buf = tokio::io::ReadBuf::new(&mut chunk[..len]);
+            let result = std::pin::Pin::new(&mut self.0).poll_read(cx, &mut chunk_buf);
+            buf.put_slice(chunk_buf.filled());
+            result
+        }
+    }
+
+    impl TransferSource for UnsizedSource {
+        fn size(&self) -> Option<u64> {
+            None
+        }
+    }
+
+    // Minimal client side of a transfer, acknowledging the OACK (if any)
+    // and every block until the last one arrives
+    async fn download(sock: &UdpSocket, block_size: usize) -> (Vec<u8>, u16) {
+        let mut contents = vec![];
+        let mut buf = vec![0; block_size + 4];
+        loop {
+            let len = sock.recv(&mut buf).await.unwrap();
+            if buf[..2] == [0, 6] {
+                sock.send(&[0, 4, 0, 0]).await.unwrap();
+                continue;
+            }
+            assert_eq!(&buf[..2], &[0, 3]);
+            let block = u16::from_be_bytes([buf[2], buf[3]]);
+            contents.extend_from_slice(&buf[4..len]);
+            sock.send(&[0, 4, buf[2], buf[3]]).await.unwrap();
+            if len - 4 < block_size {
+                return (contents, block);
+            }
+        }
+    }
+
+    fn drain(sock: &UdpSocket) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        let mut buf = [0; 1024];
+        while let Ok(len) = sock.try_recv(&mut buf) {
+            packets.push(buf[..len].to_vec());
+        }
+        packets
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unresponsive_client_stops_worker() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; 2 * BLOCK_SIZE]).await;
+
+        worker_task(connected(worker), "file.bin".into(), file, vec![], TransferSettings::default()).await;
+
+        let packets = drain(&client);
+        let data = packets.iter().filter(|packet| packet[1] == 3).collect::<Vec<_>>();
+        assert_eq!(data.len(), MAX_ATTEMPTS);
+        for packet in data {
+            assert_eq!(&packet[..4], &[0, 3, 0, 1]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn error_sent_on_retry_exhaustion() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; BLOCK_SIZE]).await;
+
+        worker_task(connected(worker), "file.bin".into(), file, vec![], TransferSettings::default()).await;
+
+        let packets = drain(&client);
+        assert_eq!(packets.len(), MAX_ATTEMPTS + 1);
+        assert_eq!(
+            packets.last().unwrap(),
+            &ErrorCode::NotDefined.into_explicit_message("Transfer aborted").into_packet()
+        );
+    }
+
+    fn test_config() -> Config {
+        Config {
+            address: DEFAULT_ADDRESS,
+            port: 0,
+            static_root: PathBuf::from("/nonexistent"),
+            virtual_files: MemoryProvider::new(),
+            path_mtu: None,
+            max_file_size: None,
+            max_tsize: None,
+            max_timeout: None,
+            file_cache: None,
+            metrics_address: None,
+            trace_file: None,
+            #[cfg(unix)]
+            chroot: false,
+            case_insensitive: false,
+            enable_listing: false,
+            hide_dotfiles: true,
+            strict_options: false,
+            auto_decompress: false,
+            single_port: false,
+            reuse_port: false,
+            roaming_clients: false,
+            traversal: TraversalPolicy::default(),
+            write_rejection: DEFAULT_WRITE_REJECTION,
+            authorizer: Authorizer::default(),
+            resolver: Resolver::default(),
+            max_lifetime: None,
+            global_rate: None,
+            workers: Arc::default(),
+            draining: Arc::default(),
+            transfer: TransferSettings::default(),
+        }
+    }
+
+    #[test]
+    fn timeout_resolution() {
+        assert_eq!(get_timeout(&[]), DEFAULT_TIMEOUT);
+        assert_eq!(get_timeout(&[TftpOption::BlockSize(1024)]), DEFAULT_TIMEOUT);
+        assert_eq!(
+            get_timeout(&[TftpOption::BlockSize(1024), TftpOption::Timeout(5)]),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            get_timeout(&[TftpOption::Timeout(5), TftpOption::MicroTimeout(250_000)]),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn port_shared_between_sockets() {
+        let config = Config { reuse_port: true, ..test_config() };
+        let first = bind_listening(&config).unwrap();
+        let config = Config { port: first.local_addr().unwrap().port(), ..config };
+        let second = bind_listening(&config).unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        // Without the option on both, the port is taken
+        let config = Config { reuse_port: false, ..config };
+        assert!(bind_listening(&config).is_err());
+    }
+
+    #[test]
+    fn timeout_grows_with_block_size() {
+        let small = effective_timeout(DEFAULT_TIMEOUT, BLOCK_SIZE);
+        let large = effective_timeout(DEFAULT_TIMEOUT, 65464);
+        assert!(small >= DEFAULT_TIMEOUT && small < DEFAULT_TIMEOUT + Duration::from_millis(10));
+        assert!(large > small + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn transfer_parameters() {
+        let options = [
+            TftpOption::TransferSize(1000),
+            TftpOption::BlockSize(1024),
+            TftpOption::Timeout(5),
+            TftpOption::Rollover(1),
+        ];
+        let parameters = Parameters::from_options(&options);
+        assert_eq!(
+            parameters,
+            Parameters { block_size: 1024, timeout: Duration::from_secs(5), rollover: 1, start_block: 1 }
+        );
+        assert_eq!(parameters.to_string(), "blksize=1024 timeout=5s rollover=1 startblock=1 windowsize=1");
+
+        assert_eq!(Parameters::from_options(&[]).to_string(), "blksize=512 timeout=3s rollover=0 startblock=1 windowsize=1");
+    }
+
+    #[test]
+    fn block_counter_rollover() {
+        assert_eq!(next_block(1, 0), 2);
+        assert_eq!(next_block(u16::MAX, 0), 0);
+        assert_eq!(next_block(0, 0), 1);
+
+        let rollover = get_rollover(&[TftpOption::Rollover(1)]);
+        assert_eq!(next_block(u16::MAX - 1, rollover), u16::MAX);
+        assert_eq!(next_block(u16::MAX, rollover), 1);
+    }
+
+    #[tokio::test]
+    async fn serve_virtual_file() {
+        let blob = (0..1500).map(|n| n as u8).collect::<Vec<_>>();
+        let config = Config {
+            virtual_files: MemoryProvider::new().file("blob", blob.clone()),
+            ..test_config()
+        };
+        let (worker, client) = connected_pair().await;
+
+        let source = open_file(&config, "blob").await.unwrap();
+        let ((contents, last_block), _) = tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+        );
+
+        assert_eq!(contents, blob);
+        assert_eq!(last_block, 3);
+    }
+
+    #[test]
+    fn block_size_clamped_to_path_mtu() {
+        let config = Config { path_mtu: Some(1500), ..test_config() };
+        let options = negotiate_options(&config, vec![
+            TftpOption::BlockSize(9000),
+            TftpOption::Timeout(2),
+        ], None).unwrap();
+
+        assert_eq!(get_block_size(&options), 1468);
+        assert_eq!(
+            Message::OptionAck { options }.into_packet(),
+            b"\x00\x06blksize\x001468\x00timeout\x002\x00"
+        );
+
+        let options = negotiate_options(&config, vec![TftpOption::BlockSize(1024)], None).unwrap();
+        assert_eq!(get_block_size(&options), 1024);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmission_backoff() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; BLOCK_SIZE]).await;
+
+        let arrivals = tokio::spawn(async move {
+            let mut arrivals = vec![];
+            let mut buf = [0; BLOCK_SIZE + 4];
+            for _ in 0..MAX_ATTEMPTS {
+                client.recv(&mut buf).await.unwrap();
+                arrivals.push(tokio::time::Instant::now());
+            }
+            arrivals
+        });
+        worker_task(connected(worker), "file.bin".into(), file, vec![], TransferSettings::default()).await;
+
+        let arrivals = arrivals.await.unwrap();
+        let intervals = arrivals.windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(intervals, [3, 6, 12, 16]);
+    }
+
+    #[tokio::test]
+    async fn transfer_size_ignored_for_unsized_source() {
+        let contents = vec![0x55; 1000];
+        let source = Box::new(UnsizedSource(std::io::Cursor::new(contents.clone())));
+        let (worker, client) = connected_pair().await;
+
+        let ((received, _), _) = tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![TftpOption::TransferSize(1000)], TransferSettings::default()),
+        );
+
+        assert_eq!(received, contents);
+    }
+
+    #[tokio::test]
+    async fn check_configuration() {
+        let root = tempfile::tempdir().unwrap();
+        let server = Server::builder().port(0).root(root.path()).build();
+        assert!(server.check().await.is_ok());
+
+        let server = Server::builder().port(0).root(root.path().join("nonexistent")).build();
+        assert!(server.check().await.is_err());
+
+        let server = Server::builder()
+            .port(0)
+            .root(root.path().join("nonexistent"))
+            .virtual_files(MemoryProvider::new().fallback(b"contents".as_slice()))
+            .build();
+        assert!(server.check().await.is_ok());
+    }
+
+    #[test]
+    fn storage_full_maps_to_disk_full() {
+        let error = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert_eq!(
+            io_error_message(&error).into_packet(),
+            b"\x00\x05\x00\x03Disk full\x00"
+        );
+    }
+
+    #[test]
+    fn transfer_size_negotiation() {
+        let config = test_config();
+
+        let options = negotiate_options(&config, vec![TftpOption::TransferSize(0)], Some(1234)).unwrap();
+        assert!(matches!(options[..], [TftpOption::TransferSize(1234)]));
+
+        let options = negotiate_options(&config, vec![TftpOption::TransferSize(0)], None).unwrap();
+        assert!(options.is_empty());
+
+        assert!(negotiate_options(&config, vec![TftpOption::TransferSize(2000)], Some(1234)).is_err());
+    }
+
+    #[test]
+    fn zero_size_probe() {
+        let config = test_config();
+        let options = vec![
+            TftpOption::BlockSize(1024),
+            TftpOption::TransferSize(0),
+            TftpOption::Timeout(2),
+            TftpOption::TransferSize(0),
+        ];
+
+        // The real size, never the 0 sent by the client, and only once
+        let options = negotiate_options(&config, options, Some(4321)).unwrap();
+        assert_eq!(
+            Message::OptionAck { options }.into_packet(),
+            b"\x00\x06blksize\x001024\x00tsize\x004321\x00timeout\x002\x00"
+        );
+    }
+
+    // Keeps every message logged while testing
+    struct CaptureLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(std::sync::Mutex::new(vec![]));
+
+    // Installs the capturing logger, if not done yet. Tests run in
+    // parallel, so others' messages show up as well
+    fn captured_logs() -> Vec<String> {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        LOGGER.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn negotiation_logged() {
+        captured_logs();
+        let peer = "127.0.0.1:3950".parse().unwrap();
+        let config = Config { path_mtu: Some(1000), ..test_config() };
+        let requested = vec![
+            TftpOption::BlockSize(1468),
+            TftpOption::Timeout(5),
+            TftpOption::Unknown { name: "windowsize".into(), value: "4".into() },
+        ];
+        let granted = negotiate_options(&config, requested.clone(), Some(2000)).unwrap();
+        log_negotiation(peer, &requested, &granted);
+
+        let logs = captured_logs();
+        for expected in [
+            "127.0.0.1:3950: requested blksize=1468, granted blksize=968",
+            "127.0.0.1:3950: requested timeout=5, granted timeout=5",
+            "127.0.0.1:3950: requested windowsize=4, not granted",
+        ] {
+            assert!(logs.iter().any(|line| line == expected), "{expected}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_start_logged() {
+        captured_logs();
+        let (worker, client) = connected_pair().await;
+        let peer = worker.peer_addr().unwrap();
+        let source = Box::new(std::io::Cursor::new(vec![1; 100]));
+
+        tokio::join!(
+            download(&client, 1024),
+            worker_task(
+                connected(worker),
+                "boot/image".into(),
+                source,
+                vec![TftpOption::BlockSize(1024), TftpOption::Timeout(5)],
+                TransferSettings::default(),
+            ),
+        );
+
+        let expected = format!("{peer}: sending boot/image (octet), blksize=1024 timeout=5s rollover=0 startblock=1 windowsize=1");
+        assert!(captured_logs().contains(&expected), "{expected}");
+    }
+
+    #[test]
+    fn timeout_capped() {
+        let config = Config { max_timeout: Some(10), ..test_config() };
+        let options = negotiate_options(&config, vec![TftpOption::Timeout(255)], None).unwrap();
+        assert_eq!(Message::OptionAck { options }.into_packet(), b"\x00\x06timeout\x0010\x00");
+
+        let options = negotiate_options(&config, vec![TftpOption::Timeout(5)], None).unwrap();
+        assert!(matches!(options[..], [TftpOption::Timeout(5)]));
+        let options = negotiate_options(&config, vec![TftpOption::MicroTimeout(60_000_000)], None).unwrap();
+        assert!(matches!(options[..], [TftpOption::MicroTimeout(10_000_000)]));
+
+        let options = negotiate_options(&test_config(), vec![TftpOption::Timeout(255)], None).unwrap();
+        assert!(matches!(options[..], [TftpOption::Timeout(255)]));
+    }
+
+    #[test]
+    fn declared_size_capped() {
+        let config = Config { max_tsize: Some(1 << 20), ..test_config() };
+        let error = negotiate_options(&config, vec![TftpOption::TransferSize(u64::MAX)], None).unwrap_err();
+        assert!(matches!(error, Message::Error { code: ErrorCode::OptionNegotiationError, .. }));
+
+        let options = negotiate_options(&config, vec![TftpOption::TransferSize(0)], Some(100)).unwrap();
+        assert!(matches!(options[..], [TftpOption::TransferSize(100)]));
+    }
+
+    #[tokio::test]
+    async fn mixed_option_negotiation() {
+        // blksize gets clamped to the path MTU, timeout is accepted as is,
+        // and the negative tsize is dropped while parsing the request
+        let request = b"\x00\x01file.bin\x00octet\x00blksize\x001468\x00timeout\x005\x00tsize\x00-1\x00";
+        let Ok(Message::Read { options, ignored, .. }) = parse_message(request) else {
+            panic!("not a read request");
+        };
+        assert_eq!(ignored, ["tsize"]);
+        let contents = (0..2000).map(|n| (n % 253) as u8).collect::<Vec<_>>();
+        let config = Config { path_mtu: Some(1000), ..test_config() };
+        let options = negotiate_options(&config, options, Some(contents.len() as u64)).unwrap();
+        assert_eq!(
+            Message::OptionAck { options: options.clone() }.into_packet(),
+            b"\x00\x06blksize\x00968\x00timeout\x005\x00"
+        );
+
+        // The transfer uses the negotiated block size
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let ((received, last_block), _) = tokio::join!(
+            download(&client, 968),
+            worker_task(connected(worker), "file.bin".into(), source, options, TransferSettings::default()),
+        );
+        assert_eq!(received, contents);
+        assert_eq!(last_block, 3);
+    }
+
+    #[tokio::test]
+    async fn repeated_request_falls_back_to_plain_transfer() {
+        let contents = vec![0x42; 1500];
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+
+        let client_side = async {
+            let mut buf = [0; 1024];
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..2], &[0, 6]);
+            client.send(b"\x00\x01file\x00octet\x00blksize\x001024\x00").await.unwrap();
+            download(&client, BLOCK_SIZE).await
+        };
+        let ((received, last_block), _) = tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![TftpOption::BlockSize(1024)], TransferSettings::default()),
+        );
+
+        assert_eq!(received, contents);
+        assert_eq!(last_block, 3);
+    }
+
+    #[tokio::test]
+    async fn forwarded_request_falls_back_to_plain_transfer() {
+        let contents = vec![0x42; 1500];
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+        let (listener, repeats) = mpsc::channel(1);
+
+        let client_side = async {
+            let mut buf = [0; 1024];
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..2], &[0, 6]);
+            // Sent again to the listening port, which hands it over
+            listener.send(b"\x00\x01file\x00octet\x00blksize\x001024\x00".to_vec()).await.unwrap();
+            download(&client, BLOCK_SIZE).await
+        };
+        let ((received, last_block), _) = tokio::join!(
+            client_side,
+            worker_task(Channel::Connected { sock: worker, repeats }, "file.bin".into(), source, vec![TftpOption::BlockSize(1024)], TransferSettings::default()),
+        );
+
+        assert_eq!(received, contents);
+        assert_eq!(last_block, 3);
+    }
+
+    #[tokio::test]
+    async fn worker_channel_errors() {
+        let peer = "127.0.0.1:2000".parse().unwrap();
+        let (_, repeats) = mpsc::channel(1);
+        let channel = worker_channel("127.0.0.1:69".parse().unwrap(), peer, false, repeats).await.unwrap();
+        assert_eq!(channel.peer_addr().unwrap(), peer);
+
+        // Not an address of this host (TEST-NET-1), so it can't be bound
+        let (_, repeats) = mpsc::channel(1);
+        assert!(worker_channel("192.0.2.1:69".parse().unwrap(), peer, false, repeats).await.is_err());
+    }
+
+    #[test]
+    fn worker_address_keeps_scope() {
+        let peer: SocketAddr = "[fe80::1%3]:2000".parse().unwrap();
+        let local: SocketAddr = "[::]:69".parse().unwrap();
+        let bound = worker_address(local, peer);
+        assert!(matches!(bound, SocketAddr::V6(addr) if addr.scope_id() == 3 && addr.port() == 0));
+
+        let local: SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let peer: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        assert_eq!(worker_address(local, peer), "127.0.0.1:0".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn short_reads_dont_end_transfer() {
+        let contents = (0..1300).map(|n| n as u8).collect::<Vec<_>>();
+        let source = Box::new(UnsizedSource(std::io::Cursor::new(contents.clone())));
+        let (worker, client) = connected_pair().await;
+
+        let ((received, last_block), _) = tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+        );
+
+        assert_eq!(received, contents);
+        assert_eq!(last_block, 3);
+    }
+
+    #[tokio::test]
+    async fn file_size_limit() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("large.bin"), vec![0; 2000]).unwrap();
+        let config = Config {
+            static_root: root.path().to_owned(),
+            max_file_size: Some(1000),
+            ..test_config()
+        };
+
+        let error = open_file(&config, "large.bin").await.err().unwrap();
+        assert!(matches!(error, Message::Error { code: ErrorCode::AccessViolation, .. }));
+
+        let config = Config { max_file_size: Some(2000), ..config };
+        assert!(open_file(&config, "large.bin").await.is_ok());
+    }
+
+    #[test]
+    fn option_ack_keeps_request_order() {
+        let config = test_config();
+        let options = negotiate_options(&config, vec![
+            TftpOption::TransferSize(0),
+            TftpOption::Timeout(4),
+            TftpOption::BlockSize(1024),
+        ], Some(100)).unwrap();
+
+        assert_eq!(
+            Message::OptionAck { options }.into_packet(),
+            b"\x00\x06tsize\x00100\x00timeout\x004\x00blksize\x001024\x00"
+        );
+    }
+
+    #[tokio::test]
+    async fn exact_multiple_of_block_size_ends_with_empty_block() {
+        for blocks in [1, 2] {
+            let contents = vec![0x33; blocks * BLOCK_SIZE];
+            let source = file_with_contents(&contents).await;
+            let (worker, client) = connected_pair().await;
+
+            let ((received, last_block), _) = tokio::join!(
+                download(&client, BLOCK_SIZE),
+                worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+            );
+
+            // The last block carries no data at all
+            assert_eq!(received, contents);
+            assert_eq!(last_block as usize, blocks + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_files_served() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.bin"), vec![0x11; 700]).unwrap();
+        let config = Config {
+            static_root: root.path().to_owned(),
+            file_cache: Some(FileCache::new(2)),
+            ..test_config()
+        };
+
+        for _ in 0..2 {
+            let source = open_file(&config, "file.bin").await.ok().unwrap();
+            let (worker, client) = connected_pair().await;
+            let ((received, _), _) = tokio::join!(
+                download(&client, BLOCK_SIZE),
+                worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+            );
+            assert_eq!(received, vec![0x11; 700]);
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_updated_by_transfer() {
+        let settings = TransferSettings::default();
+        let metrics = &settings.metrics;
+        let contents = vec![0x77; 1300];
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+
+        tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], settings.clone()),
+        );
+
+        assert_eq!(metrics.total_transfers(), 1);
+        assert_eq!(metrics.active_transfers(), 0);
+        assert_eq!(metrics.total_bytes_sent(), 1300);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn metrics_count_errors() {
+        let settings = TransferSettings::default();
+        let metrics = &settings.metrics;
+        let (worker, _client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; BLOCK_SIZE]).await;
+
+        worker_task(connected(worker), "file.bin".into(), file, vec![], settings.clone()).await;
+
+        assert_eq!(metrics.errors(ErrorCode::NotDefined), 1);
+        assert_eq!(metrics.total_bytes_sent(), 0);
+    }
+
+    #[tokio::test]
+    async fn refused_connection_aborts_transfer() {
+        let worker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        worker.connect(gone.local_addr().unwrap()).await.unwrap();
+        drop(gone);
+        let file = file_with_contents(&[0xaa; BLOCK_SIZE]).await;
+
+        // Without noticing, it would take several timeouts to give up
+        let finished = tokio::time::timeout(
+            Duration::from_secs(1),
+            worker_task(connected(worker), "file.bin".into(), file, vec![], TransferSettings::default()),
+        ).await;
+        assert!(finished.is_ok());
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_lookup() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.bin"), b"contents").unwrap();
+        let config = Config { static_root: root.path().to_owned(), ..test_config() };
+
+        let error = open_file(&config, "FILE.BIN").await.err().unwrap();
+        assert!(matches!(error, Message::Error { code: ErrorCode::FileNotFound, .. }));
+
+        let config = Config { case_insensitive: true, ..config };
+        let mut source = open_file(&config, "FILE.BIN").await.ok().unwrap();
+        assert_eq!(read_block(&mut source, BLOCK_SIZE).await.unwrap(), b"contents");
+
+        // Ambiguous names are not resolved
+        std::fs::write(root.path().join("File.bin"), b"other").unwrap();
+        assert!(open_file(&config, "FILE.BIN").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn gzipped_files_decompressed() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncWriteExt;
+
+        let contents = (0..2000).map(|n| (n % 7) as u8).collect::<Vec<_>>();
+        let mut encoder = GzipEncoder::new(vec![]);
+        encoder.write_all(&contents).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.bin.gz"), encoder.into_inner()).unwrap();
+        let config = Config { static_root: root.path().to_owned(), ..test_config() };
+
+        let error = open_file(&config, "file.bin").await.err().unwrap();
+        assert!(matches!(error, Message::Error { code: ErrorCode::FileNotFound, .. }));
+
+        let config = Config { auto_decompress: true, ..config };
+        let mut source = open_file(&config, "file.bin").await.ok().unwrap();
+        assert_eq!(source.size(), None);
+        let mut decompressed = vec![];
+        source.read_to_end(&mut decompressed).await.unwrap();
+        assert_eq!(decompressed, contents);
+
+        // The decompressed length is reported for tsize, and measuring
+        // doesn't disturb the contents
+        let mut source = open_file(&config, "file.bin").await.ok().unwrap();
+        let options = vec![TftpOption::TransferSize(0)];
+        let size = transfer_size(&mut source, &options).await.unwrap();
+        assert_eq!(size, Some(contents.len() as u64));
+        assert!(matches!(
+            negotiate_options(&config, options, size).unwrap()[..],
+            [TftpOption::TransferSize(2000)]
+        ));
+        let mut decompressed = vec![];
+        source.read_to_end(&mut decompressed).await.unwrap();
+        assert_eq!(decompressed, contents);
+    }
+
+    #[tokio::test]
+    async fn generated_files_size_reported() {
+        let provider = MemoryProvider::new().generated("boot.cfg", || vec![b'x'; 1234]);
+        let config = Config { virtual_files: provider, ..test_config() };
+
+        // Known before sending anything, so there's nothing to measure
+        let mut source = open_file(&config, "boot.cfg").await.ok().unwrap();
+        assert_eq!(transfer_size(&mut source, &[]).await.unwrap(), Some(1234));
+        let options = vec![TftpOption::TransferSize(0)];
+        let size = transfer_size(&mut source, &options).await.unwrap();
+        assert!(matches!(
+            negotiate_options(&config, options, size).unwrap()[..],
+            [TftpOption::TransferSize(1234)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn root_made_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        // Up to the filesystem root from the working directory, and then
+        // down to the temporary one
+        let cwd = std::env::current_dir().unwrap();
+        let mut relative = PathBuf::from(".");
+        for _ in cwd.components().filter(|part| matches!(part, Component::Normal(..))) {
+            relative.push("..");
+        }
+        relative.extend(dir.path().components().filter(|part| matches!(part, Component::Normal(..))));
+        assert!(relative.is_relative());
+
+        let server = Server { config: Config { static_root: relative, ..test_config() } };
+        let listener = server.bind().await.unwrap();
+        assert_eq!(listener.config.static_root, dir.path().canonicalize().unwrap());
+    }
+
+    #[test]
+    fn path_resolution() {
+        let root = Path::new("/srv/tftp");
+        assert_eq!(resolve_path(root, "boot/image", TraversalPolicy::Lexical).ok(), Some(PathBuf::from("/srv/tftp/boot/image")));
+        assert_eq!(resolve_path(root, "./image", TraversalPolicy::Lexical).ok(), Some(PathBuf::from("/srv/tftp/image")));
+        assert_eq!(resolve_path(root, "/boot/image", TraversalPolicy::Lexical).ok(), Some(PathBuf::from("/srv/tftp/boot/image")));
+        assert_eq!(resolve_path(root, "//image", TraversalPolicy::Lexical).ok(), Some(PathBuf::from("/srv/tftp/image")));
+
+        for filename in ["..", "../etc/passwd", "boot/../../etc/passwd", "/../etc/passwd"] {
+            let error = resolve_path(root, filename, TraversalPolicy::Lexical).err().unwrap();
+            assert!(matches!(error, Message::Error { code: ErrorCode::AccessViolation, .. }), "{filename}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn traversal_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("boot")).unwrap();
+        std::fs::write(root.join("image"), b"").unwrap();
+        std::fs::write(dir.path().join("escape"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("escape"), root.join("link")).unwrap();
+        let is_violation = |result: Result<PathBuf, Message>| {
+            matches!(result, Err(Message::Error { code: ErrorCode::AccessViolation, .. }))
+        };
+
+        // Lexical: no parent directories at all, but symlinks go unnoticed
+        assert!(is_violation(resolve_path(&root, "../escape", TraversalPolicy::Lexical)));
+        assert!(is_violation(resolve_path(&root, "boot/../image", TraversalPolicy::Lexical)));
+        assert!(resolve_path(&root, "link", TraversalPolicy::Lexical).is_ok());
+
+        // Canonical: judged by where the file actually is
+        assert!(is_violation(resolve_path(&root, "../escape", TraversalPolicy::Canonical)));
+        assert!(is_violation(resolve_path(&root, "link", TraversalPolicy::Canonical)));
+        assert!(resolve_path(&root, "boot/../image", TraversalPolicy::Canonical).is_ok());
+        assert!(is_violation(resolve_path(&root, "../missing", TraversalPolicy::Canonical)));
+
+        // Chroot: left to the jail
+        assert_eq!(
+            resolve_path(&root, "../escape", TraversalPolicy::TrustChroot).ok(),
+            Some(root.join("../escape"))
+        );
+    }
+
+    #[tokio::test]
+    async fn paths_relative_to_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("image"), b"image").unwrap();
+        std::fs::write(dir.path().join("escape"), b"secret").unwrap();
+        let config = Config { static_root: root, ..test_config() };
+
+        let mut source = open_file(&config, "/image").await.ok().unwrap();
+        assert_eq!(read_block(&mut source, BLOCK_SIZE).await.unwrap(), b"image");
+
+        for filename in ["../escape", "/../escape", "sub/../../escape"] {
+            let error = open_file(&config, filename).await.err().unwrap();
+            assert!(matches!(error, Message::Error { code: ErrorCode::AccessViolation, .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn client_specific_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("pxelinux.cfg")).unwrap();
+        std::fs::write(root.path().join("pxelinux.cfg/default"), b"default").unwrap();
+        std::fs::write(root.path().join("pxelinux.cfg/10.0.0.5"), b"host").unwrap();
+        let resolver = Resolver(Some(Arc::new(|peer: SocketAddr, filename: &str| {
+            (filename == "pxelinux.cfg/default").then(|| format!("pxelinux.cfg/{}", peer.ip()))
+        })));
+        let config = Config { static_root: root.path().to_owned(), resolver, ..test_config() };
+
+        let known = "10.0.0.5:2000".parse().unwrap();
+        let mut source = open_for_client(&config, known, "pxelinux.cfg/default").await.ok().unwrap();
+        assert_eq!(read_block(&mut source, BLOCK_SIZE).await.unwrap(), b"host");
+
+        // Clients without a file of their own get the requested one
+        let unknown = "10.0.0.6:2000".parse().unwrap();
+        let mut source = open_for_client(&config, unknown, "pxelinux.cfg/default").await.ok().unwrap();
+        assert_eq!(read_block(&mut source, BLOCK_SIZE).await.unwrap(), b"default");
+    }
+
+    #[tokio::test]
+    async fn dotfiles_hidden() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(".secret"), b"secret").unwrap();
+        std::fs::create_dir(root.path().join("subdir")).unwrap();
+        std::fs::write(root.path().join("subdir/.hidden"), b"hidden").unwrap();
+        std::fs::create_dir(root.path().join(".git")).unwrap();
+        std::fs::write(root.path().join(".git/config"), b"config").unwrap();
+        std::fs::write(root.path().join("visible"), b"visible").unwrap();
+        let config = Config { static_root: root.path().to_owned(), ..test_config() };
+
+        for filename in [".secret", "subdir/.hidden", "/.secret", ".git/config"] {
+            let error = open_file(&config, filename).await.err().unwrap();
+            assert!(matches!(error, Message::Error { code: ErrorCode::FileNotFound, .. }), "{filename}");
+        }
+        assert!(open_file(&config, "./visible").await.is_ok());
+
+        let config = Config { hide_dotfiles: false, ..config };
+        for filename in [".secret", "subdir/.hidden", ".git/config"] {
+            assert!(open_file(&config, filename).await.is_ok(), "{filename}");
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_listing() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("boot")).unwrap();
+        std::fs::write(root.path().join("boot/pxelinux.0"), b"").unwrap();
+        std::fs::create_dir(root.path().join("boot/pxelinux.cfg")).unwrap();
+        std::fs::write(root.path().join("boot/.hidden"), b"").unwrap();
+        let config = Config { static_root: root.path().to_owned(), ..test_config() };
+
+        let error = open_file(&config, "boot").await.err().unwrap();
+        assert!(matches!(error, Message::Error { code: ErrorCode::AccessViolation, .. }));
+
+        let config = Config { enable_listing: true, ..config };
+        let mut source = open_file(&config, "boot").await.ok().unwrap();
+        assert_eq!(
+            read_block(&mut source, BLOCK_SIZE).await.unwrap(),
+            b"pxelinux.0\npxelinux.cfg/\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacknowledged_options_fall_back_to_plain_transfer() {
+        let contents = vec![0x24; 700];
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+        let settings = TransferSettings { oack_fallback: true, ..Default::default() };
+
+        // The client ignores every OACK, but handles the data
+        let client_side = async {
+            let mut buf = [0; 1024];
+            let mut oacks = 0;
+            loop {
+                let len = client.recv(&mut buf).await.unwrap();
+                if buf[..2] == [0, 6] {
+                    oacks += 1;
+                    continue;
+                }
+                client.send(&[0, 4, buf[2], buf[3]]).await.unwrap();
+                if len - 4 < BLOCK_SIZE {
+                    return oacks;
+                }
+            }
+        };
+        let (oacks, _) = tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![TftpOption::BlockSize(1024)], settings),
+        );
+
+        assert_eq!(oacks, MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn rejected_options_not_fallen_back_from() {
+        let source = Box::new(std::io::Cursor::new(vec![0x24; 700]));
+        let (worker, client) = connected_pair().await;
+        let settings = TransferSettings { oack_fallback: true, ..Default::default() };
+
+        let client_side = async {
+            let mut buf = [0; 1024];
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..2], &[0, 6]);
+            client.send(b"\x00\x05\x00\x08Option refused\x00").await.unwrap();
+        };
+        tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![TftpOption::BlockSize(1024)], settings),
+        );
+
+        // Neither data nor an error of its own
+        assert!(drain(&client).is_empty());
+    }
+
+    #[tokio::test]
+    async fn acks_for_unsent_blocks_ignored() {
+        let contents = (0..5 * BLOCK_SIZE + 100).map(|n| (n % 253) as u8).collect::<Vec<_>>();
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+
+        let client_side = async {
+            let mut buf = [0; BLOCK_SIZE + 4];
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+            // Blocks 2 and 3 were never sent, so nothing is skipped
+            client.send(&[0, 4, 0, 3]).await.unwrap();
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+            let mut received = contents[..BLOCK_SIZE].to_vec();
+            let (rest, last_block) = download(&client, BLOCK_SIZE).await;
+            received.extend(rest);
+            (received, last_block)
+        };
+        let ((received, last_block), _) = tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+        );
+
+        assert_eq!(received, contents);
+        assert_eq!(last_block, 6);
+    }
+
+    #[tokio::test]
+    async fn transfers_take_turns() {
+        let queue = Arc::new(SendQueue::new(1));
+        let settings = TransferSettings { sends: Some(queue.clone()), ..Default::default() };
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The test keeps the only turn between rounds, so that both
+        // transfers are waiting for one when it's let go
+        let mut turn = Some(queue.turn().await);
+        let mut workers = vec![];
+        let mut tasks = vec![];
+        for fill in [b'a', b'b'] {
+            let worker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            worker.connect(client.local_addr().unwrap()).await.unwrap();
+            workers.push(worker.local_addr().unwrap());
+            let source = Box::new(Cursor::new(vec![fill; 2 * BLOCK_SIZE]));
+            tasks.push(tokio::spawn(worker_task(connected(worker), "file.bin".into(), source, vec![], settings.clone())));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut buf = [0; 516];
+        let mut order = vec![];
+        for _ in 1..=3 {
+            assert!(tokio::time::timeout(Duration::from_millis(50), client.recv_from(&mut buf)).await.is_err());
+            drop(turn.take());
+            let mut received = vec![];
+            for _ in &workers {
+                let (_, from) = client.recv_from(&mut buf).await.unwrap();
+                received.push((from, [buf[2], buf[3]]));
+            }
+
+            // Acknowledged in the order they arrived, so that they ask
+            // for their next turn in that same order
+            turn = Some(queue.turn().await);
+            for (from, block) in received {
+                order.push(workers.iter().position(|&worker| worker == from).unwrap());
+                client.send_to(&[0, 4, block[0], block[1]], from).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        drop(turn);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(order, [0, 1, 0, 1, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn options_disabled() {
+        let contents = vec![0x61; 700];
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+        let settings = TransferSettings { no_options: true, ..Default::default() };
+
+        let client_side = async {
+            let mut buf = [0; 1024];
+            client.recv(&mut buf).await.unwrap();
+            // Straight to the data, with the default block size
+            assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+            download(&client, BLOCK_SIZE).await
+        };
+        let ((rest, last_block), _) = tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![TftpOption::BlockSize(1024)], settings),
+        );
+
+        assert_eq!(rest, &contents[BLOCK_SIZE..]);
+        assert_eq!(last_block, 2);
+
+        let config = Config {
+            transfer: TransferSettings { no_options: true, ..Default::default() },
+            ..test_config()
+        };
+        assert!(negotiate_options(&config, vec![TftpOption::TransferSize(2000)], Some(1234)).unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmissions_reported() {
+        let (worker, client) = connected_pair().await;
+        let mut channel = connected(worker);
+        let settings = TransferSettings::default();
+
+        let client_side = async {
+            let mut buf = [0; 16];
+            // Only the third copy gets an answer
+            for _ in 0..3 {
+                client.recv(&mut buf).await.unwrap();
+            }
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+        };
+        let (_, result) = tokio::join!(
+            client_side,
+            packet_and_ack(&mut channel, 1, &[0, 3, 0, 1], DEFAULT_TIMEOUT, &settings),
+        );
+
+        assert!(matches!(result.unwrap(), (Response::Ack, 2)));
+
+        let mut retries = RetryStats::default();
+        for attempts in [2, 0, 1] {
+            retries.record(attempts);
+        }
+        assert_eq!((retries.max, retries.total), (2, 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_final_ack_recovered() {
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(vec![0x5a; BLOCK_SIZE + 10]));
+
+        let client_side = async {
+            let mut buf = [0; BLOCK_SIZE + 4];
+            client.recv(&mut buf).await.unwrap();
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+            // The ACK for the final block gets lost the first time, so
+            // the worker has to send the block again
+            let len = client.recv(&mut buf).await.unwrap();
+            let last = buf[..len].to_vec();
+            let len = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], last);
+            client.send(&[0, 4, 0, 2]).await.unwrap();
+        };
+        let started = tokio::time::Instant::now();
+        tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+        );
+
+        // Done after a single retransmission
+        assert!(started.elapsed() < 2 * DEFAULT_TIMEOUT);
+        assert!(drain(&client).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_packets_rejected() {
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(Cursor::new(vec![1; 2000]));
+
+        let client_side = async {
+            let mut buf = [0; 1024];
+            client.recv(&mut buf).await.unwrap();
+            // An ACK, followed by lots of garbage
+            let mut bogus = vec![0, 4, 0, 1];
+            bogus.resize(1000, 0xff);
+            client.send(&bogus).await.unwrap();
+            let len = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"\x00\x05\x00\x04Packet too large\x00");
+        };
+        tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+        );
+
+        // The transfer is over, and nothing follows the error
+        assert!(drain(&client).is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_acks_rejected() {
+        for bogus in [&[0, 4, 0][..], &[0, 4, 0, 1, 0]] {
+            let (worker, client) = connected_pair().await;
+            let source = Box::new(Cursor::new(vec![1; 2000]));
+
+            let client_side = async {
+                let mut buf = [0; 1024];
+                client.recv(&mut buf).await.unwrap();
+                client.send(bogus).await.unwrap();
+                let len = client.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], b"\x00\x05\x00\x04Malformed ACK\x00");
+            };
+            tokio::join!(
+                client_side,
+                worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default()),
+            );
+
+            // The transfer is over, and nothing follows the error
+            assert!(drain(&client).is_empty(), "{bogus:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_datagrams_ignored() {
+        let (worker, client) = connected_pair().await;
+        let mut channel = connected(worker);
+        let settings = TransferSettings::default();
+
+        let client_side = async {
+            let mut buf = [0; 16];
+            client.recv(&mut buf).await.unwrap();
+            client.send(&[]).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+        };
+        let (_, result) = tokio::join!(
+            client_side,
+            packet_and_ack(&mut channel, 1, &[0, 3, 0, 1], DEFAULT_TIMEOUT, &settings),
+        );
+
+        // Neither answered nor counted as a failed attempt
+        assert!(matches!(result.unwrap(), (Response::Ack, 0)));
+        assert!(drain(&client).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wrong_acks_do_not_extend_wait() {
+        let (worker, client) = connected_pair().await;
+        let mut channel = connected(worker);
+        let settings = TransferSettings::default();
+
+        // Stale ACKs, arriving faster than the timeout
+        let flood = tokio::spawn(async move {
+            loop {
+                client.send(&[0, 4, 0, 1]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        let started = Instant::now();
+        let result = packet_and_ack(&mut channel, 2, &[0, 3, 0, 2], DEFAULT_TIMEOUT, &settings).await;
+        flood.abort();
+
+        assert!(result.is_err());
+        // Same as if nothing had arrived: 3 + 6 + 12 + 16 + 16 seconds
+        assert!(started.elapsed() < Duration::from_secs(54));
+    }
+
+    #[tokio::test]
+    async fn packets_traced() {
+        let trace_file = tempfile::NamedTempFile::new().unwrap();
+        let settings = TransferSettings {
+            trace: Some(Arc::new(Trace::create(trace_file.path()).unwrap())),
+            ..Default::default()
+        };
+        let source = Box::new(std::io::Cursor::new(vec![0x10; 1300]));
+        let (worker, client) = connected_pair().await;
+
+        tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], settings),
+        );
+
+        // Three DATA packets, and their ACKs
+        let trace = std::fs::read_to_string(trace_file.path()).unwrap();
+        let records = trace.lines().collect::<Vec<_>>();
+        assert_eq!(records.len(), 6);
+        assert!(records[0].contains(" > ") && records[0].contains(" 516 00 03 00 01 10 10 "));
+        assert!(records[1].contains(" < ") && records[1].ends_with(" 4 00 04 00 01"));
+    }
+
+    #[test]
+    fn transient_receive_errors() {
+        for kind in [std::io::ErrorKind::Interrupted, std::io::ErrorKind::WouldBlock, std::io::ErrorKind::ConnectionReset] {
+            assert!(is_transient(&kind.into()));
+        }
+        assert!(!is_transient(&std::io::ErrorKind::PermissionDenied.into()));
+    }
+
+    #[test]
+    fn huge_transfer_size() {
+        let config = test_config();
+        let Ok(Message::Read { options, .. }) = parse_message(b"\x00\x01f\x00octet\x00tsize\x0018446744073709551615\x00") else {
+            panic!("request not parsed");
+        };
+        assert!(matches!(options[..], [TftpOption::TransferSize(u64::MAX)]));
+
+        assert!(negotiate_options(&config, options.clone(), Some(1234)).is_err());
+        assert!(negotiate_options(&config, options.clone(), Some(u64::MAX)).is_ok());
+        assert!(negotiate_options(&config, options, None).unwrap().is_empty());
+
+        // One more than that doesn't even parse
+        let Ok(Message::Read { options, ignored, .. }) = parse_message(b"\x00\x01f\x00octet\x00tsize\x0018446744073709551616\x00") else {
+            panic!("request not parsed");
+        };
+        assert!(options.is_empty() && ignored == ["tsize"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn jail_moves_root() {
+        let mut config = Config { static_root: PathBuf::from("/srv/tftp"), ..test_config() };
+        let mut jailed_into = None;
+        jail(&mut config, |root| {
+            jailed_into = Some(root.to_owned());
+            Ok(())
+        }).unwrap();
+        assert_eq!(jailed_into.unwrap(), Path::new("/srv/tftp"));
+        assert_eq!(config.static_root, Path::new("/"));
+
+        // Nothing changes if it fails
+        let mut config = Config { static_root: PathBuf::from("/srv/tftp"), ..test_config() };
+        assert!(jail(&mut config, |_| Err(std::io::ErrorKind::PermissionDenied.into())).is_err());
+        assert_eq!(config.static_root, Path::new("/srv/tftp"));
+    }
+
+    #[test]
+    fn error_packets() {
+        let settings = TransferSettings::default();
+        let cases: [(Message, &[u8]); 4] = [
+            (ErrorCode::FileNotFound.into_message(), b"\x00\x05\x00\x01File not found\x00"),
+            (ErrorCode::IllegalOperation.into_explicit_message("No write permission"), b"\x00\x05\x00\x04No write permission\x00"),
+            (ErrorCode::UnknownTransferId.into_message(), b"\x00\x05\x00\x05Unknown TID\x00"),
+            (ErrorCode::OptionNegotiationError.into_explicit_message(""), b"\x00\x05\x00\x08\x00"),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(error_packet(message, &settings), expected);
+        }
+        assert_eq!(settings.metrics.errors(ErrorCode::FileNotFound), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn micro_timeout_negotiated() {
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(vec![0; 100]));
+        let options = negotiate_options(&test_config(), vec![TftpOption::MicroTimeout(250_000)], None).unwrap();
+
+        let client_side = async {
+            let mut buf = [0; 64];
+            let len = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"\x00\x06utimeout\x00250000\x00");
+            // Wait for the retransmission, instead of acknowledging
+            let start = tokio::time::Instant::now();
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(start.elapsed(), Duration::from_millis(250));
+            client.send(&[0, 4, 0, 0]).await.unwrap();
+            download(&client, BLOCK_SIZE).await
+        };
+        let ((received, _), _) = tokio::join!(
+            client_side,
+            worker_task(connected(worker), "file.bin".into(), source, options, TransferSettings::default()),
+        );
+
+        assert_eq!(received, vec![0; 100]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacknowledged_options_abort_transfer() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; BLOCK_SIZE]).await;
+
+        worker_task(connected(worker), "file.bin".into(), file, vec![TftpOption::BlockSize(1024)], TransferSettings::default()).await;
+
+        let packets = drain(&client);
+        assert_eq!(packets.len(), MAX_ATTEMPTS + 1);
+        assert!(packets[..MAX_ATTEMPTS].iter().all(|packet| packet[..2] == [0, 6]));
+        assert_eq!(
+            packets.last().unwrap(),
+            &ErrorCode::NotDefined.into_explicit_message("Transfer aborted").into_packet()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_error_stops_transfer() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; 3 * BLOCK_SIZE]).await;
+
+        let client_side = async {
+            let mut buf = [0; BLOCK_SIZE + 4];
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..4], &[0, 3, 0, 1]);
+            client.send(b"\x00\x05\x00\x03Disk full\x00").await.unwrap();
+        };
+        tokio::join!(client_side, worker_task(connected(worker), "file.bin".into(), file, vec![], TransferSettings::default()));
+
+        // No more data, and no error in return
+        assert!(drain(&client).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn size_probe_then_abort() {
+        let (worker, client) = connected_pair().await;
+        let file = file_with_contents(&[0xaa; 3 * BLOCK_SIZE]).await;
+        let options = negotiate_options(&test_config(), vec![TftpOption::TransferSize(0)], file.size()).unwrap();
+        let settings = TransferSettings::default();
+
+        // The client only wants to know the size, and declines the
+        // transfer as soon as it gets it
+        let client_side = async {
+            let mut buf = [0; BLOCK_SIZE + 4];
+            let len = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"\x00\x06tsize\x001536\x00");
+            client.send(b"\x00\x05\x00\x08Just probing\x00").await.unwrap();
+        };
+        let started = tokio::time::Instant::now();
+        tokio::join!(client_side, worker_task(connected(worker), "file.bin".into(), file, options, settings.clone()));
+
+        // Ends right away, without retransmissions or any data sent
+        assert!(started.elapsed() < DEFAULT_TIMEOUT);
+        assert!(drain(&client).is_empty());
+        assert_eq!(settings.metrics.total_bytes_sent(), 0);
+    }
+
+    #[tokio::test]
+    async fn completion_hook() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let settings = TransferSettings {
+            completion: {
+                let reports = reports.clone();
+                Completion(Some(Arc::new(move |stats| reports.lock().unwrap().push(stats))))
+            },
+            ..Default::default()
+        };
+
+        let contents = vec![5; 1300];
+        let (worker, client) = connected_pair().await;
+        let client_addr = client.local_addr().unwrap();
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], settings.clone()),
+        );
+
+        // The client gives up after the first block
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(contents));
+        let client_side = async {
+            let mut buf = [0; 516];
+            client.recv(&mut buf).await.unwrap();
+            client.send(&[0, 4, 0, 1]).await.unwrap();
+            client.recv(&mut buf).await.unwrap();
+            client.send(b"\x00\x05\x00\x00Cancelled\x00").await.unwrap();
+        };
+        tokio::join!(
+            client_side,
+            worker_task(connected(worker), "other.bin".into(), source, vec![], settings),
+        );
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].client, Some(client_addr));
+        assert_eq!(reports[0].filename, "file.bin");
+        assert_eq!(reports[0].bytes, 1300);
+        assert!(reports[0].success);
+        assert_eq!(reports[1].filename, "other.bin");
+        assert_eq!(reports[1].bytes, 512);
+        assert!(!reports[1].success);
+    }
+
+    #[tokio::test]
+    async fn progress_reported() {
+        let (events, mut progress) = mpsc::channel(16);
+        let settings = TransferSettings {
+            progress: Some(Progress { events, interval: Duration::ZERO }),
+            ..Default::default()
+        };
+
+        let (worker, client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(vec![1; 1100]));
+        tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, vec![], settings),
+        );
+
+        let mut reported = vec![];
+        while let Ok(event) = progress.try_recv() {
+            assert_eq!(event.filename, "file.bin");
+            assert_eq!(event.size, Some(1100));
+            reported.push(event.bytes);
+        }
+        assert_eq!(reported, [512, 1024, 1100]);
+    }
+
+    #[test]
+    fn progress_percentage() {
+        let progress = TransferProgress { client: None, filename: "file.bin".into(), bytes: 256, size: Some(1024) };
+        assert_eq!(progress.percent(), Some(25.0));
+        assert_eq!(TransferProgress { size: None, ..progress.clone() }.percent(), None);
+        assert_eq!(TransferProgress { bytes: 0, size: Some(0), ..progress }.percent(), Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn transfer_from_start_block() {
+        let contents = (0..4 * BLOCK_SIZE + 100).map(|n| (n % 249) as u8).collect::<Vec<_>>();
+        let config = test_config();
+        let options = negotiate_options(&config, vec![TftpOption::StartBlock(3)], Some(contents.len() as u64)).unwrap();
+        assert_eq!(Message::OptionAck { options: options.clone() }.into_packet(), b"\x00\x06startblock\x003\x00");
+        let source = Box::new(std::io::Cursor::new(contents.clone()));
+        let (worker, client) = connected_pair().await;
+
+        let ((received, last_block), _) = tokio::join!(
+            download(&client, BLOCK_SIZE),
+            worker_task(connected(worker), "file.bin".into(), source, options, TransferSettings::default()),
+        );
+
+        assert_eq!(received, &contents[2 * BLOCK_SIZE..]);
+        assert_eq!(last_block, 5);
+
+        assert!(negotiate_options(&config, vec![TftpOption::StartBlock(7)], Some(contents.len() as u64)).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn workers_over_lifetime_are_cancelled() {
+        let registry = Arc::new(WorkerRegistry::default());
+        tokio::spawn(registry.clone().run_reaper(Duration::from_secs(10)));
+
+        // The client never answers, so the worker would keep retrying
+        // for much longer than the lifetime on its own
+        let (worker, _client) = connected_pair().await;
+        let source = Box::new(std::io::Cursor::new(vec![0; 100]));
+        let handle = registry.register();
+        let started = tokio::time::Instant::now();
+        let finished = handle.supervise(worker_task(connected(worker), "file.bin".into(), source, vec![], TransferSettings::default())).await;
+
+        assert!(!finished);
+        let elapsed = started.elapsed();
+        assert!(elapsed > Duration::from_secs(10) && elapsed < Duration::from_secs(12));
+        assert!(registry.is_empty());
+    }
+}
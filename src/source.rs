@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io::{Cursor, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader, ReadBuf},
+};
+
+/// Anything that can feed the contents of a transfer, block by block
+pub trait TransferSource: AsyncRead + Send + Unpin {
+    /// Length of the contents, if known before starting the transfer
+    fn size(&self) -> Option<u64>;
+
+    /// Works out the length of the contents when `size` doesn't know it,
+    /// for sources that can do it at some cost. Must be called before
+    /// reading anything
+    fn measure(&mut self) -> Pin<Box<dyn Future<Output = std::io::Result<Option<u64>>> + Send + '_>> {
+        let size = self.size();
+        Box::pin(async move { Ok(size) })
+    }
+
+    /// Moves past the first `bytes` of the contents. Must be called before
+    /// reading anything. Sources that can't seek read them and throw them
+    /// away
+    fn skip(&mut self, bytes: u64) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            discard(self, bytes).await?;
+            Ok(())
+        })
+    }
+}
+
+// Reads and throws away up to `limit` bytes, returning how many there
+// were. Decompressing seldom needs to wait for the file, so it yields
+// now and then, or nothing else would get to run meanwhile
+async fn discard<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, limit: u64) -> std::io::Result<u64> {
+    let mut reader = reader.take(limit);
+    let mut discarded = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(discarded);
+        }
+        discarded += len as u64;
+        tokio::task::yield_now().await;
+    }
+}
+
+/// A file on disk, along with the length reported by its metadata. The
+/// length is only trusted for regular files: special files (devices,
+/// pipes, procfs entries...) often report 0 or some arbitrary value
+pub struct FileSource {
+    file: File,
+    size: Option<u64>,
+}
+
+impl FileSource {
+    pub async fn new(file: File) -> std::io::Result<Self> {
+        let metadata = file.metadata().await?;
+        let size = metadata.is_file().then_some(metadata.len());
+
+        Ok(FileSource { file, size })
+    }
+}
+
+impl AsyncRead for FileSource {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+impl TransferSource for FileSource {
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    // Only regular files (the ones with a size) can seek
+    fn skip(&mut self, bytes: u64) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            if self.size.is_some() {
+                self.file.seek(SeekFrom::Start(bytes)).await?;
+            } else {
+                discard(&mut self.file, bytes).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A gzip compressed file, decompressed as it's read. The length of the
+/// contents can't be known without decompressing the whole file first
+pub struct GzipSource {
+    decoder: GzipDecoder<BufReader<File>>,
+    size: Option<u64>,
+}
+
+impl GzipSource {
+    pub fn new(file: File) -> Self {
+        GzipSource { decoder: GzipDecoder::new(BufReader::new(file)), size: None }
+    }
+}
+
+impl AsyncRead for GzipSource {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.decoder).poll_read(cx, buf)
+    }
+}
+
+impl TransferSource for GzipSource {
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    // Decompresses the whole file once, just to count the bytes
+    fn measure(&mut self) -> Pin<Box<dyn Future<Output = std::io::Result<Option<u64>>> + Send + '_>> {
+        Box::pin(async move {
+            if self.size.is_none() {
+                // The clone shares the file offset, which has to be
+                // restored afterwards
+                let mut file = self.decoder.get_ref().get_ref().try_clone().await?;
+                let mut decoder = GzipDecoder::new(BufReader::new(&mut file));
+                let size = discard(&mut decoder, u64::MAX).await?;
+                file.seek(SeekFrom::Start(0)).await?;
+                self.size = Some(size);
+            }
+
+            Ok(self.size)
+        })
+    }
+}
+
+impl<T: AsRef<[u8]> + Send + Unpin> TransferSource for Cursor<T> {
+    fn size(&self) -> Option<u64> {
+        Some(self.get_ref().as_ref().len() as u64)
+    }
+
+    fn skip(&mut self, bytes: u64) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + '_>> {
+        self.set_position(bytes);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[derive(Clone)]
+enum VirtualFile {
+    Contents(Arc<Vec<u8>>),
+    Generated(Arc<dyn Fn() -> Vec<u8> + Send + Sync>),
+}
+
+/// Serves files that don't exist in the filesystem, either from fixed
+/// contents, or generated on demand every time they're requested
+#[derive(Clone, Default)]
+pub struct MemoryProvider {
+    files: HashMap<String, VirtualFile>,
+    // Served for any name not found in `files`
+    fallback: Option<VirtualFile>,
+}
+
+impl MemoryProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn file(mut self, filename: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(filename.into(), VirtualFile::Contents(Arc::new(contents.into())));
+        self
+    }
+
+    /// Contents served for any filename that has no contents of its own
+    pub fn fallback(mut self, contents: impl Into<Vec<u8>>) -> Self {
+        self.fallback = Some(VirtualFile::Contents(Arc::new(contents.into())));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.fallback.is_none()
+    }
+
+    pub fn generated<F>(mut self, filename: &str, generator: F) -> Self
+    where
+        F: Fn() -> Vec<u8> + Send + Sync + 'static
+    {
+        self.files.insert(filename.into(), VirtualFile::Generated(Arc::new(generator)));
+        self
+    }
+
+    pub fn open(&self, filename: &str) -> Option<Cursor<Vec<u8>>> {
+        self.files.get(filename).or(self.fallback.as_ref()).map(|vfile| {
+            Cursor::new(match vfile {
+                VirtualFile::Contents(contents) => contents.as_ref().clone(),
+                VirtualFile::Generated(generator) => generator(),
+            })
+        })
+    }
+}
+
+impl fmt::Debug for MemoryProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = f.debug_set();
+        names.entries(self.files.keys());
+        if self.fallback.is_some() {
+            names.entry(&"*");
+        }
+        names.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_provider_contents() {
+        let provider = MemoryProvider::new()
+            .file("fixed", b"fixed contents".as_slice())
+            .generated("generated", || b"generated".to_vec());
+
+        let mut buffer = vec![];
+        let mut source = provider.open("fixed").unwrap();
+        source.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, b"fixed contents");
+
+        let source = provider.open("generated").unwrap();
+        assert_eq!(source.size(), Some(9));
+
+        assert!(provider.open("missing").is_none());
+
+        let provider = provider.fallback(b"anything".as_slice());
+        let mut buffer = vec![];
+        provider.open("missing").unwrap().read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, b"anything");
+    }
+
+    #[tokio::test]
+    async fn skipping_contents() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncWriteExt;
+
+        let contents = (0..2000).map(|n| (n % 7) as u8).collect::<Vec<_>>();
+        let mut encoder = GzipEncoder::new(vec![]);
+        encoder.write_all(&contents).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.bin"), &contents).unwrap();
+        std::fs::write(dir.path().join("file.bin.gz"), encoder.into_inner()).unwrap();
+
+        let file = File::open(dir.path().join("file.bin")).await.unwrap();
+        let gzip = File::open(dir.path().join("file.bin.gz")).await.unwrap();
+        let sources: Vec<Box<dyn TransferSource>> = vec![
+            Box::new(FileSource::new(file).await.unwrap()),
+            Box::new(GzipSource::new(gzip)),
+            Box::new(Cursor::new(contents.clone())),
+        ];
+        for mut source in sources {
+            source.skip(1500).await.unwrap();
+            let mut rest = vec![];
+            source.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, &contents[1500..]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn special_files_have_no_size() {
+        let source = FileSource::new(File::open("/dev/zero").await.unwrap()).await.unwrap();
+        assert_eq!(source.size(), None);
+    }
+}
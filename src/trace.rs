@@ -0,0 +1,54 @@
+use std::{
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Appends a timestamped hex dump of packets to a file, one per line:
+///
+/// ```text
+/// 1700000000.123456 > 127.0.0.1:50000 4 00 04 00 01
+/// ```
+#[derive(Debug)]
+pub struct Trace {
+    file: Mutex<File>,
+}
+
+impl Trace {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+
+        Ok(Trace { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, direction: Direction, peer: SocketAddr, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let arrow = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        let hex = packet.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" ");
+        let line = format!(
+            "{}.{:06} {arrow} {peer} {} {hex}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            packet.len()
+        );
+
+        // Write the whole line at once, so that records don't interleave
+        if let Err(error) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("While writing to the trace file: {error}");
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use tftpd::{client::Client, TftpOption, MAX_BLOCK_SIZE};
+
+mod common;
+use common::serve_root;
+
+#[tokio::test]
+async fn download_with_client() {
+    let root = tempfile::tempdir().unwrap();
+    let contents = (0..3000).map(|n| (n % 241) as u8).collect::<Vec<_>>();
+    std::fs::write(root.path().join("file.bin"), &contents).unwrap();
+    let server_addr = serve_root(root.path()).await;
+
+    let local = root.path().join("downloaded.bin");
+    let client = Client::new(server_addr).option(TftpOption::BlockSize(1024));
+    assert_eq!(client.download("file.bin", &local).await.unwrap(), 3000);
+    assert_eq!(std::fs::read(&local).unwrap(), contents);
+
+    assert!(client.download("missing.bin", &local).await.is_err());
+}
+
+#[tokio::test]
+async fn download_with_default_options() {
+    let root = tempfile::tempdir().unwrap();
+    let contents = (0..3000).map(|n| (n % 239) as u8).collect::<Vec<_>>();
+    std::fs::write(root.path().join("file.bin"), &contents).unwrap();
+    let server_addr = serve_root(root.path()).await;
+
+    let mut received = vec![];
+    assert_eq!(Client::new(server_addr).get("file.bin", &mut received).await.unwrap(), 3000);
+    assert_eq!(received, contents);
+}
+
+#[tokio::test]
+async fn download_at_block_boundary() {
+    let root = tempfile::tempdir().unwrap();
+    let server_addr = serve_root(root.path()).await;
+
+    // A file filling the last block is followed by an empty one
+    let client = Client::new(server_addr);
+    for size in [1023, 1024] {
+        let contents = vec![7; size];
+        std::fs::write(root.path().join("file.bin"), &contents).unwrap();
+        let mut received = vec![];
+        assert_eq!(client.get("file.bin", &mut received).await.unwrap(), size as u64);
+        assert_eq!(received, contents);
+    }
+}
+
+#[tokio::test]
+async fn download_with_largest_blocks() {
+    let root = tempfile::tempdir().unwrap();
+    let size = 3 * MAX_BLOCK_SIZE as usize + 100;
+    let contents = (0..size).map(|n| (n % 233) as u8).collect::<Vec<_>>();
+    std::fs::write(root.path().join("file.bin"), &contents).unwrap();
+    let server_addr = serve_root(root.path()).await;
+
+    let client = Client::new(server_addr).option(TftpOption::BlockSize(MAX_BLOCK_SIZE));
+    let mut received = vec![];
+    assert_eq!(client.get("file.bin", &mut received).await.unwrap(), size as u64);
+    assert_eq!(received, contents);
+}
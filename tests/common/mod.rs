@@ -0,0 +1,21 @@
+//! Helpers shared by the integration tests
+
+use std::{net::SocketAddr, path::Path};
+
+use tftpd::server::Server;
+
+// Starts a server on an ephemeral port, returning its address once it's
+// ready to accept requests
+pub async fn start_server(server: Server) -> SocketAddr {
+    let listener = server.bind().await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    addr
+}
+
+// Serves `root` with the default settings
+#[allow(dead_code)]
+pub async fn serve_root(root: &Path) -> SocketAddr {
+    start_server(Server::builder().port(0).root(root).build()).await
+}
@@ -0,0 +1,507 @@
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Duration};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+use tftpd::{server::{Listener, Server}, source::MemoryProvider, ErrorCode};
+
+mod common;
+use common::start_server;
+
+#[tokio::test]
+async fn bound_address_is_reported() {
+    let root = tempfile::tempdir().unwrap();
+    let addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    assert_ne!(addr.port(), 0);
+}
+
+#[tokio::test]
+async fn download_file() {
+    let root = tempfile::tempdir().unwrap();
+    let contents = (0..1300).map(|n| (n % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(root.path().join("file.bin"), &contents).unwrap();
+
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    assert_eq!(download(server_addr, "file.bin").await, contents);
+}
+
+// Downloads a file without options, acknowledging every block
+async fn download(server_addr: SocketAddr, filename: &str) -> Vec<u8> {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(format!("\x00\x01{filename}\x00octet\x00").as_bytes(), server_addr).await.unwrap();
+
+    let mut buf = [0; 516];
+    let (mut len, worker) = client.recv_from(&mut buf).await.unwrap();
+    client.connect(worker).await.unwrap();
+
+    let mut received = vec![];
+    loop {
+        assert_eq!(&buf[..2], &[0, 3]);
+        received.extend_from_slice(&buf[4..len]);
+        client.send(&[0, 4, buf[2], buf[3]]).await.unwrap();
+        if len < buf.len() {
+            break;
+        }
+        len = client.recv(&mut buf).await.unwrap();
+    }
+
+    received
+}
+
+// Sends a packet to the listening socket, returning the reply (if any)
+async fn exchange(server_addr: SocketAddr, packet: &[u8]) -> Option<Vec<u8>> {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(packet, server_addr).await.unwrap();
+
+    let mut buf = [0; 516];
+    timeout(Duration::from_secs(1), client.recv(&mut buf)).await
+        .ok()
+        .map(|len| buf[..len.unwrap()].to_vec())
+}
+
+#[tokio::test(start_paused = true)]
+async fn stray_packets_on_listening_socket() {
+    let root = tempfile::tempdir().unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    assert_eq!(exchange(server_addr, b"\x00\x04\x00\x01").await, None);
+    assert_eq!(exchange(server_addr, b"\x00\x05\x00\x00Abort\x00").await, None);
+
+    let reply = exchange(server_addr, b"\x00\x03\x00\x01data").await.unwrap();
+    assert_eq!(&reply[..4], &[0, 5, 0, 5]);
+}
+
+// Builds a request of exactly `size` bytes, padding it with unknown
+// options before asking for the transfer size
+fn request_of_size(filename: &str, size: usize) -> Vec<u8> {
+    let mut request = format!("\x00\x01{filename}\x00octet\x00").into_bytes();
+    let suffix = b"tsize\x000\x00";
+    // Padding option: "x...x\0y\0"
+    let padding = size - request.len() - suffix.len() - 3;
+    request.resize(request.len() + padding, b'x');
+    request.extend_from_slice(b"\x00y\x00");
+    request.extend_from_slice(suffix);
+    assert_eq!(request.len(), size);
+
+    request
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_up_to_maximum_size() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), vec![0; 100]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    let reply = exchange(server_addr, &request_of_size("file.bin", 512)).await.unwrap();
+    assert_eq!(reply, b"\x00\x06tsize\x00100\x00");
+
+    let reply = exchange(server_addr, &request_of_size("file.bin", 513)).await.unwrap();
+    assert_eq!(&reply[..4], &[0, 5, 0, 4]);
+}
+
+#[tokio::test]
+async fn shutdown_without_traffic() {
+    let root = tempfile::tempdir().unwrap();
+    let listener = Server::builder().port(0).root(root.path()).build().bind().await.unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(listener.run_until(async {
+        let _ = stopped.await;
+    }));
+
+    stop.send(()).unwrap();
+    let result = timeout(Duration::from_secs(1), server).await;
+    assert!(matches!(result, Ok(Ok(Ok(())))));
+}
+
+#[tokio::test]
+async fn download_empty_file() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("empty"), b"").unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"\x00\x01empty\x00octet\x00tsize\x000\x00", server_addr).await.unwrap();
+
+    let mut buf = [0; 516];
+    let (len, worker) = client.recv_from(&mut buf).await.unwrap();
+    client.connect(worker).await.unwrap();
+    assert_eq!(&buf[..len], b"\x00\x06tsize\x000\x00");
+
+    client.send(&[0, 4, 0, 0]).await.unwrap();
+    let len = client.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], &[0, 3, 0, 1]);
+    client.send(&[0, 4, 0, 1]).await.unwrap();
+}
+
+#[tokio::test]
+async fn missing_root_fails_at_startup() {
+    let root = tempfile::tempdir().unwrap();
+    let missing = root.path().join("missing");
+
+    let server = Server::builder().port(0).root(&missing).build();
+    assert!(server.bind().await.is_err());
+
+    // Nothing is read from the root when there are virtual files
+    let server = Server::builder()
+        .port(0)
+        .root(&missing)
+        .virtual_files(MemoryProvider::new().file("file.bin", b"contents".to_vec()))
+        .build();
+    assert!(server.bind().await.is_ok());
+}
+
+#[tokio::test]
+async fn authorization_hook() {
+    let root = tempfile::tempdir().unwrap();
+    for name in ["public.bin", "secret.bin"] {
+        std::fs::write(root.path().join(name), b"contents").unwrap();
+    }
+    let server = Server::builder()
+        .port(0)
+        .root(root.path())
+        .authorize(|_, filename| async move { filename != "secret.bin" })
+        .build();
+    let server_addr = start_server(server).await;
+
+    let reply = exchange(server_addr, b"\x00\x01secret.bin\x00octet\x00").await.unwrap();
+    assert_eq!(&reply[..4], &[0, 5, 0, 2]);
+
+    let reply = exchange(server_addr, b"\x00\x01public.bin\x00octet\x00").await.unwrap();
+    assert_eq!(reply, b"\x00\x03\x00\x01contents");
+}
+
+#[tokio::test]
+async fn slow_authorization_hook() {
+    let root = tempfile::tempdir().unwrap();
+    for name in ["slow.bin", "fast.bin"] {
+        std::fs::write(root.path().join(name), b"contents").unwrap();
+    }
+    let (release, released) = tokio::sync::watch::channel(false);
+    let server = Server::builder()
+        .port(0)
+        .root(root.path())
+        .authorize(move |_, filename| {
+            let mut released = released.clone();
+            async move {
+                if filename == "slow.bin" {
+                    released.wait_for(|&released| released).await.unwrap();
+                }
+                true
+            }
+        })
+        .build();
+    let server_addr = start_server(server).await;
+
+    // Stuck until the hook is let go, without holding back anyone else
+    let slow = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    slow.send_to(b"\x00\x01slow.bin\x00octet\x00", server_addr).await.unwrap();
+    let reply = exchange(server_addr, b"\x00\x01fast.bin\x00octet\x00").await.unwrap();
+    assert_eq!(reply, b"\x00\x03\x00\x01contents");
+
+    release.send(true).unwrap();
+    let mut buf = [0; 516];
+    let (len, worker) = slow.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"\x00\x03\x00\x01contents");
+    slow.send_to(&[0, 4, 0, 1], worker).await.unwrap();
+}
+
+#[tokio::test]
+async fn single_port_transfers() {
+    let root = tempfile::tempdir().unwrap();
+    let contents = [vec![1; 700], vec![2; 1100]];
+    std::fs::write(root.path().join("one.bin"), &contents[0]).unwrap();
+    std::fs::write(root.path().join("two.bin"), &contents[1]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).single_port(true).build()).await;
+
+    let clients = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    clients[0].send_to(b"\x00\x01one.bin\x00octet\x00", server_addr).await.unwrap();
+    clients[1].send_to(b"\x00\x01two.bin\x00octet\x00", server_addr).await.unwrap();
+
+    // Both transfers progress in lockstep, and every packet comes from
+    // the listening port
+    let mut received = [vec![], vec![]];
+    let mut done = [false, false];
+    let mut buf = [0; 516];
+    while done != [true, true] {
+        for (n, client) in clients.iter().enumerate() {
+            if done[n] {
+                continue;
+            }
+            let (len, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from, server_addr);
+            assert_eq!(&buf[..2], &[0, 3]);
+            received[n].extend_from_slice(&buf[4..len]);
+            client.send_to(&[0, 4, buf[2], buf[3]], server_addr).await.unwrap();
+            done[n] = len < buf.len();
+        }
+    }
+
+    assert_eq!(received, contents);
+}
+
+#[tokio::test(start_paused = true)]
+async fn write_requests_rejected() {
+    let root = tempfile::tempdir().unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+    let reply = exchange(server_addr, b"\x00\x02file.bin\x00octet\x00").await.unwrap();
+    assert_eq!(reply, b"\x00\x05\x00\x02No write permission\x00");
+
+    let server = Server::builder().port(0).root(root.path()).write_rejection(ErrorCode::IllegalOperation).build();
+    let server_addr = start_server(server).await;
+    let reply = exchange(server_addr, b"\x00\x02file.bin\x00octet\x00").await.unwrap();
+    assert_eq!(&reply[..4], &[0, 5, 0, 4]);
+}
+
+#[tokio::test]
+async fn global_rate_exceeded() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), b"contents").unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).global_rate(Some(2)).build()).await;
+
+    for _ in 0..2 {
+        let reply = exchange(server_addr, b"\x00\x01file.bin\x00octet\x00").await.unwrap();
+        assert_eq!(reply, b"\x00\x03\x00\x01contents");
+    }
+    let reply = exchange(server_addr, b"\x00\x01file.bin\x00octet\x00").await.unwrap();
+    assert_eq!(reply, b"\x00\x05\x00\x00Server busy\x00");
+}
+
+// Runs the server binary with the given environment, returning the
+// configuration it prints
+fn printed_config(env: &[(&str, &str)], args: &[&str]) -> String {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_tftpd"))
+        .env_remove("TFTPD_ADDRESS")
+        .env_remove("TFTPD_PORT")
+        .env_remove("TFTPD_ROOT")
+        .envs(env.iter().copied())
+        .args(args)
+        .arg("--print-config")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn settings_from_environment() {
+    let env = [("TFTPD_ADDRESS", "0.0.0.0"), ("TFTPD_PORT", "6969"), ("TFTPD_ROOT", "/tmp/tftp")];
+    let printed = printed_config(&env, &[]);
+    assert!(printed.contains("address = \"0.0.0.0\""));
+    assert!(printed.contains("port = 6969"));
+    assert!(printed.contains("root = \"/tmp/tftp\""));
+
+    // Flags win over the environment
+    let printed = printed_config(&env, &["--port", "69"]);
+    assert!(printed.contains("port = 69\n"));
+}
+
+#[tokio::test]
+async fn serve_stdin() {
+    use std::{io::Write, process::{Command, Stdio}};
+
+    // Grab a free port for the server binary, which doesn't report it
+    let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_tftpd"))
+        .args(["--port", &port.to_string(), "--serve-stdin"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    server.stdin.take().unwrap().write_all(b"piped contents").unwrap();
+
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut reply = None;
+    for _ in 0..20 {
+        reply = exchange(server_addr, b"\x00\x01any/name\x00octet\x00").await;
+        if reply.is_some() {
+            break;
+        }
+    }
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(reply.unwrap(), b"\x00\x03\x00\x01piped contents");
+}
+
+#[tokio::test]
+async fn drain_mode() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), vec![1; 700]).unwrap();
+    let listener = Server::builder().port(0).root(root.path()).build().bind().await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let draining = listener.draining();
+    tokio::spawn(listener.run());
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"\x00\x01file.bin\x00octet\x00", server_addr).await.unwrap();
+    let mut buf = [0; 516];
+    let (len, worker) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(len, 516);
+
+    draining.store(true, Ordering::Relaxed);
+    let reply = exchange(server_addr, b"\x00\x01file.bin\x00octet\x00").await.unwrap();
+    assert_eq!(reply, b"\x00\x05\x00\x00Server busy\x00");
+
+    // The transfer in progress is not affected
+    client.send_to(&[0, 4, 0, 1], worker).await.unwrap();
+    let len = client.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..4], &[0, 3, 0, 2]);
+    assert_eq!(len, 4 + 700 - 512);
+    client.send_to(&[0, 4, 0, 2], worker).await.unwrap();
+
+    draining.store(false, Ordering::Relaxed);
+    assert_eq!(download(server_addr, "file.bin").await, vec![1; 700]);
+}
+
+#[tokio::test]
+async fn roaming_client() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), vec![3; 700]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).roaming_clients(true).build()).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"\x00\x01file.bin\x00octet\x00", server_addr).await.unwrap();
+    let mut buf = [0; 516];
+    let (len, worker) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(len, 516);
+
+    // The client carries on from another port
+    let moved = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    moved.send_to(&[0, 4, 0, 1], worker).await.unwrap();
+    let (len, from) = moved.recv_from(&mut buf).await.unwrap();
+    assert_eq!(from, worker);
+    assert_eq!(&buf[..4], &[0, 3, 0, 2]);
+    assert_eq!(len, 4 + 700 - 512);
+    moved.send_to(&[0, 4, 0, 2], worker).await.unwrap();
+}
+
+#[tokio::test]
+async fn serve_from_socket() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), b"contents").unwrap();
+
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = sock.local_addr().unwrap();
+    // The port in the configuration is not used
+    let listener = Listener::from_socket(sock, Server::builder().port(1).root(root.path()).build()).await.unwrap();
+    assert_eq!(listener.local_addr().unwrap(), server_addr);
+    tokio::spawn(listener.run());
+
+    assert_eq!(download(server_addr, "file.bin").await, b"contents");
+}
+
+#[tokio::test(start_paused = true)]
+async fn garbage_options_in_strict_mode() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), b"contents").unwrap();
+    let request = b"\x00\x01file.bin\x00octet\x00\xff\xfe\x00blksize\x00huge\x00";
+
+    // Served as if there were no options
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+    let reply = exchange(server_addr, request).await.unwrap();
+    assert_eq!(reply, b"\x00\x03\x00\x01contents");
+
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).strict_options(true).build()).await;
+    let reply = exchange(server_addr, request).await.unwrap();
+    assert_eq!(&reply[..4], &[0, 5, 0, 8]);
+}
+
+#[tokio::test]
+async fn repeated_request_served_once() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), vec![4; 700]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for _ in 0..2 {
+        client.send_to(b"\x00\x01file.bin\x00octet\x00", server_addr).await.unwrap();
+    }
+    let mut buf = [0; 516];
+    let (len, worker) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((len, &buf[..4]), (516, &[0, 3, 0, 1][..]));
+
+    // No second worker starts sending the same file
+    assert!(timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await.is_err());
+
+    client.send_to(&[0, 4, 0, 1], worker).await.unwrap();
+    let (len, from) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((len, from), (4 + 700 - 512, worker));
+    client.send_to(&[0, 4, 0, 2], worker).await.unwrap();
+}
+
+// A client that can't see the OACK asks again, from the same port
+#[tokio::test]
+async fn repeated_request_without_options() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("file.bin"), vec![4; 700]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    let request = b"\x00\x01file.bin\x00octet\x00blksize\x001024\x00";
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(request, server_addr).await.unwrap();
+    let mut buf = [0; 1028];
+    let (len, worker) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"\x00\x06blksize\x001024\x00");
+
+    client.send_to(request, server_addr).await.unwrap();
+    let (len, from) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((len, &buf[..4], from), (516, &[0, 3, 0, 1][..], worker));
+    client.send_to(&[0, 4, 0, 1], worker).await.unwrap();
+    let (len, from) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((len, &buf[..4], from), (4 + 700 - 512, &[0, 3, 0, 2][..], worker));
+    client.send_to(&[0, 4, 0, 2], worker).await.unwrap();
+}
+
+#[tokio::test]
+async fn new_request_while_being_served() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("first.bin"), vec![4; 700]).unwrap();
+    std::fs::write(root.path().join("second.bin"), vec![5; 10]).unwrap();
+    let server_addr = start_server(Server::builder().port(0).root(root.path()).build()).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"\x00\x01first.bin\x00octet\x00", server_addr).await.unwrap();
+    let mut buf = [0; 516];
+    let (len, first) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((len, &buf[..4]), (516, &[0, 3, 0, 1][..]));
+
+    // Not a repeat of the first request, so it gets a transfer of its own
+    client.send_to(b"\x00\x01second.bin\x00octet\x00", server_addr).await.unwrap();
+    let (len, second) = client.recv_from(&mut buf).await.unwrap();
+    assert_ne!(first, second);
+    assert_eq!(&buf[..len], &[0, 3, 0, 1, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5]);
+    client.send_to(&[0, 4, 0, 1], second).await.unwrap();
+}
+
+#[tokio::test]
+async fn served_while_measuring() {
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::AsyncWriteExt;
+
+    let root = tempfile::tempdir().unwrap();
+    let mut encoder = GzipEncoder::new(vec![]);
+    for _ in 0..64 {
+        encoder.write_all(&[0; 1 << 20]).await.unwrap();
+    }
+    encoder.shutdown().await.unwrap();
+    std::fs::write(root.path().join("large.bin.gz"), encoder.into_inner()).unwrap();
+    std::fs::write(root.path().join("small.bin"), b"small").unwrap();
+    let server = Server::builder().port(0).root(root.path()).auto_decompress(true).build();
+    let server_addr = start_server(server).await;
+
+    // Takes the whole decompression to answer, which doesn't hold back
+    // other clients
+    let large = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    large.send_to(b"\x00\x01large.bin\x00octet\x00tsize\x000\x00", server_addr).await.unwrap();
+    assert_eq!(exchange(server_addr, b"\x00\x01small.bin\x00octet\x00").await.unwrap(), b"\x00\x03\x00\x01small");
+    let mut buf = [0; 516];
+    assert!(large.try_recv(&mut buf).is_err());
+
+    let (len, worker) = large.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"\x00\x06tsize\x0067108864\x00");
+    large.send_to(b"\x00\x05\x00\x00\x00", worker).await.unwrap();
+}